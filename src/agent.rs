@@ -0,0 +1,294 @@
+use nix::libc::c_void;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::sys::mman::{mlock, munlock};
+use nix::unistd::getuid;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+// The agent speaks a line based protocol, one request per connection:
+//
+//   get ID                        -> ok KEY | none
+//   add ID KEY IDLE_TIMEOUT MAX_LIFETIME -> ok
+//   lock                          -> ok
+//
+// ID identifies both the repository and the contents of its .sala/key,
+// so a cached key is never used after the master key has been changed.
+// KEY is hex encoded, and timeouts are in seconds (0 means no limit).
+
+const MAX_REQUEST_LENGTH: u64 = 4096;
+
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("SALA_AGENT_SOCK") {
+        return PathBuf::from(path);
+    }
+    dirs::runtime_dir()
+        .map(|dir| dir.join("sala"))
+        .unwrap_or_else(|| env::temp_dir().join(format!("sala-{}", getuid())))
+        .join("agent.sock")
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let pairs = data.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    let mut result = Vec::with_capacity(data.len() / 2);
+    for pair in pairs {
+        let digits = std::str::from_utf8(pair).ok()?;
+        result.push(u8::from_str_radix(digits, 16).ok()?);
+    }
+    Some(result)
+}
+
+fn wipe(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+fn key_id(repo_path: &Path) -> io::Result<String> {
+    let repo = fs::canonicalize(repo_path)?;
+    let mut hasher = Sha256::new();
+    hasher.update(repo.as_os_str().as_bytes());
+    hasher.update([0]);
+    hasher.update(fs::read(repo_path.join(".sala/key"))?);
+    Ok(hex_encode(&hasher.finalize()))
+}
+
+// Client
+
+fn request(line: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
+
+pub fn is_running() -> bool {
+    UnixStream::connect(socket_path()).is_ok()
+}
+
+/// Asks the agent for the master key of the repository
+pub fn get_key(repo_path: &Path) -> Option<Vec<u8>> {
+    let id = key_id(repo_path).ok()?;
+    let mut response = request(&format!("get {}", id)).ok()?;
+    let key = response
+        .strip_prefix("ok ")
+        .and_then(|key| hex_decode(key.as_bytes()));
+    unsafe { wipe(response.as_bytes_mut()) };
+    key
+}
+
+/// Hands the master key of the repository to the agent, if one is running
+pub fn add_key(repo_path: &Path, key: &[u8], idle_timeout: u64, max_lifetime: u64) {
+    if let Ok(id) = key_id(repo_path) {
+        let mut line = format!(
+            "add {} {} {} {}",
+            id,
+            hex_encode(key),
+            idle_timeout,
+            max_lifetime
+        );
+        let _ = request(&line);
+        unsafe { wipe(line.as_bytes_mut()) };
+    }
+}
+
+/// Makes the agent forget all keys
+pub fn lock() -> io::Result<()> {
+    request("lock").map(|_| ())
+}
+
+// Server
+
+struct CachedKey {
+    key: Vec<u8>,
+    added: Instant,
+    last_used: Instant,
+    idle_timeout: u64,
+    max_lifetime: u64,
+}
+
+impl CachedKey {
+    fn new(key: Vec<u8>, idle_timeout: u64, max_lifetime: u64) -> CachedKey {
+        // Keep the key out of swap. This is best effort, as the limit for
+        // locked memory may be very low.
+        unsafe {
+            let _ = mlock(key.as_ptr() as *const c_void, key.len());
+        }
+        let now = Instant::now();
+        CachedKey {
+            key,
+            added: now,
+            last_used: now,
+            idle_timeout,
+            max_lifetime,
+        }
+    }
+
+    fn expires(&self) -> Option<Instant> {
+        // A timeout too large to represent never expires
+        let idle = Some(self.idle_timeout)
+            .filter(|&t| t > 0)
+            .and_then(|t| self.last_used.checked_add(Duration::from_secs(t)));
+        let max = Some(self.max_lifetime)
+            .filter(|&t| t > 0)
+            .and_then(|t| self.added.checked_add(Duration::from_secs(t)));
+        idle.into_iter().chain(max).min()
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expires(), Some(t) if t <= now)
+    }
+}
+
+impl Drop for CachedKey {
+    fn drop(&mut self) {
+        wipe(&mut self.key);
+        unsafe {
+            let _ = munlock(self.key.as_ptr() as *const c_void, self.key.len());
+        }
+    }
+}
+
+/// Creates the agent socket, readable and writable by the current user
+/// only
+pub fn listen(path: &Path) -> io::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let metadata = fs::metadata(dir)?;
+        if metadata.uid() != getuid().as_raw() || metadata.mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Insecure permissions on {}", dir.to_string_lossy()),
+            ));
+        }
+    }
+
+    // Not running (checked by the caller), so the socket is stale
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn is_same_user(stream: &UnixStream) -> bool {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .map(|cred| cred.uid() == getuid().as_raw())
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_same_user(_stream: &UnixStream) -> bool {
+    // Rely on the permissions of the socket
+    true
+}
+
+fn handle(mut stream: UnixStream, keys: &mut HashMap<String, CachedKey>) -> io::Result<()> {
+    if !is_same_user(&stream) {
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut line = Vec::new();
+    BufReader::new(&stream)
+        .take(MAX_REQUEST_LENGTH)
+        .read_until(b'\n', &mut line)?;
+
+    let mut response = {
+        let words: Vec<&[u8]> = line
+            .split(|&b| b == b' ' || b == b'\n')
+            .filter(|w| !w.is_empty())
+            .collect();
+        match words.as_slice() {
+            [b"get", id] => {
+                // The key may have expired while the request was waiting
+                let now = Instant::now();
+                keys.retain(|_, cached| !cached.is_expired(now));
+                match keys.get_mut(&*String::from_utf8_lossy(id)) {
+                    Some(cached) => {
+                        cached.last_used = now;
+                        format!("ok {}", hex_encode(&cached.key))
+                    }
+                    None => "none".to_string(),
+                }
+            }
+            [b"add", id, key, idle_timeout, max_lifetime] => {
+                let timeout = |t: &[u8]| String::from_utf8_lossy(t).parse::<u64>().ok();
+                match (
+                    hex_decode(key),
+                    timeout(idle_timeout),
+                    timeout(max_lifetime),
+                ) {
+                    (Some(key), Some(idle_timeout), Some(max_lifetime)) => {
+                        keys.insert(
+                            String::from_utf8_lossy(id).to_string(),
+                            CachedKey::new(key, idle_timeout, max_lifetime),
+                        );
+                        "ok".to_string()
+                    }
+                    _ => "error".to_string(),
+                }
+            }
+            [b"lock"] => {
+                keys.clear();
+                "ok".to_string()
+            }
+            _ => "error".to_string(),
+        }
+    };
+    wipe(&mut line);
+
+    response.push('\n');
+    let result = stream.write_all(response.as_bytes());
+    unsafe { wipe(response.as_bytes_mut()) };
+    result
+}
+
+/// Serves requests until an unrecoverable error occurs
+pub fn serve(listener: UnixListener) -> io::Result<()> {
+    let mut keys: HashMap<String, CachedKey> = HashMap::new();
+    loop {
+        let now = Instant::now();
+        keys.retain(|_, cached| !cached.is_expired(now));
+
+        // Wake up when the next key expires
+        let timeout = keys
+            .values()
+            .filter_map(CachedKey::expires)
+            .min()
+            .map(|t| ((t - now).as_millis() + 1).min(i32::MAX as u128) as i32)
+            .unwrap_or(-1);
+        let mut fds = [PollFd::new(listener.as_raw_fd(), EventFlags::POLLIN)];
+        match poll(&mut fds, timeout) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(err) => return Err(io::Error::other(err.to_string())),
+        }
+
+        if let Ok((stream, _)) = listener.accept() {
+            let _ = handle(stream, &mut keys);
+        }
+    }
+}
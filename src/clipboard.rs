@@ -0,0 +1,77 @@
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{self, ForkResult};
+use std::io::{self, Write};
+use std::process::Stdio;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::command;
+
+fn paste(paste_cmd: &str) -> io::Result<Vec<u8>> {
+    let output = command::parse(paste_cmd)?.stderr(Stdio::null()).output()?;
+    Ok(output.stdout)
+}
+
+fn copy(copy_cmd: &str, data: &[u8]) -> io::Result<()> {
+    // Clipboard tools like xclip stay in the background to serve the
+    // clipboard, so they must not hold on to our stdout
+    let mut child = command::parse(copy_cmd)?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(data)?;
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{} failed with {}",
+            copy_cmd, status
+        )))
+    }
+}
+
+fn detach() -> nix::Result<()> {
+    unistd::setsid()?;
+    let null = open("/dev/null", OFlag::O_RDWR, Mode::empty())?;
+    for fd in 0..3 {
+        unistd::dup2(null, fd)?;
+    }
+    unistd::close(null)
+}
+
+/// Copies the secret to the clipboard. After `clear_after` seconds (unless
+/// 0), a background process puts the previous contents of the clipboard
+/// back, or clears it if they are not known. The clipboard is left alone
+/// if its contents have changed in the meantime.
+pub fn copy_secret(
+    copy_cmd: &str,
+    paste_cmd: Option<&str>,
+    secret: &[u8],
+    clear_after: u64,
+) -> io::Result<()> {
+    let previous = paste_cmd.and_then(|cmd| paste(cmd).ok());
+    copy(copy_cmd, secret)?;
+    if clear_after == 0 {
+        return Ok(());
+    }
+
+    match unistd::fork() {
+        Ok(ForkResult::Parent { .. }) => Ok(()),
+        Ok(ForkResult::Child) => {
+            let _ = detach();
+            sleep(Duration::from_secs(clear_after));
+            let unchanged = match paste_cmd {
+                Some(cmd) => paste(cmd).map(|current| current == secret).unwrap_or(true),
+                None => true,
+            };
+            if unchanged {
+                let _ = copy(copy_cmd, &previous.unwrap_or_default());
+            }
+            std::process::exit(0);
+        }
+        Err(err) => Err(io::Error::other(err.to_string())),
+    }
+}
@@ -0,0 +1,19 @@
+use std::io;
+use std::process::Command;
+
+/// Parses a command line from the config or the environment, like
+/// `xclip -selection clipboard`, into a command to run
+pub fn parse(cmd: &str) -> io::Result<Command> {
+    let parsed_cmd = shell_words::split(cmd)
+        .ok()
+        .filter(|words| !words.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid command: {}", cmd),
+            )
+        })?;
+    let mut command = Command::new(&parsed_cmd[0]);
+    command.args(&parsed_cmd[1..]);
+    Ok(command)
+}
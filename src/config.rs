@@ -1,76 +1,792 @@
 use dirs;
-use serde::Deserialize;
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 
+use crate::gpg;
+
 #[derive(Debug)]
 pub struct Error {
     pub path: PathBuf,
     pub error: toml::de::Error,
 }
 
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    Native,
+    Gpg,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PasswordStyle {
+    Chars,
+    Words,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digits,
+    Symbols,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookPolicy {
+    Warn,
+    Abort,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Settings that apply to the secrets whose path matches a glob pattern
+#[derive(Clone)]
+pub struct Rule {
+    pub pattern: String,
+    pub source: PathBuf,
+    pub settings: toml::value::Table,
+    glob: glob::Pattern,
+}
+
+// The settings that can be changed per secret
+const RULE_SETTINGS: &[&str] = &[
+    "cipher",
+    "password-generator",
+    "password-suggestions",
+    "password-style",
+    "password-length",
+    "password-classes",
+    "password-exclude-ambiguous",
+    "password-wordlist",
+    "password-words",
+    "password-separator",
+    "hook-timeout",
+    "hook-policy",
+];
+
+impl Rule {
+    /// Returns the settings of the rule in TOML syntax
+    pub fn settings_toml(&self) -> String {
+        toml::Value::Table(self.settings.clone()).to_string()
+    }
+
+    /// Returns true if the rule comes from the store's config
+    pub fn is_from_repo(&self) -> bool {
+        self.source.ends_with(".sala/config")
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.glob.matches_path_with(path, options)
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
+    pub backend: Backend,
     pub cipher: String,
     pub key_length: usize,
     pub password_generator: Option<String>,
+    pub password_suggestions: usize,
+    pub password_style: PasswordStyle,
+    pub password_length: usize,
+    pub password_classes: Vec<CharClass>,
+    pub password_exclude_ambiguous: bool,
+    pub password_wordlist: Option<PathBuf>,
+    pub password_words: usize,
+    pub password_separator: String,
+    pub agent_idle_timeout: u64,
+    pub agent_max_lifetime: u64,
+    pub clipboard_copy: Option<String>,
+    pub clipboard_paste: Option<String>,
+    pub clipboard_clear_after: u64,
+    pub git_auto_commit: bool,
+    pub hook_timeout: u64,
+    pub hook_policy: HashMap<String, HookPolicy>,
+    pub rules: Vec<Rule>,
+
+    // Not read from the config files, but from the command line and the
+    // environment
+    pub passphrase_fd: Option<RawFd>,
+    pub passphrase_file: Option<PathBuf>,
+    pub format: Format,
+
+    // Set if the command comes from the store's config, which must be
+    // trusted before it's run
+    pub password_generator_from_repo: bool,
+    pub clipboard_copy_from_repo: bool,
+    pub clipboard_paste_from_repo: bool,
+
+    // Set if password-generator comes from a rule, to its pattern
+    pub password_generator_rule: Option<String>,
+
+    // The hook policies from the store's config, which only apply to the
+    // store's own hooks
+    pub repo_hook_policy: HashMap<String, HookPolicy>,
 }
 
 impl Config {
     fn merge(self, other: ConfigFromFile) -> Config {
         Config {
+            backend: other.backend.unwrap_or(self.backend),
             cipher: other.cipher.unwrap_or(self.cipher),
             key_length: other.key_length.unwrap_or(self.key_length),
             password_generator: other.password_generator.or(self.password_generator),
+            password_suggestions: other
+                .password_suggestions
+                .unwrap_or(self.password_suggestions),
+            password_style: other.password_style.unwrap_or(self.password_style),
+            password_length: other.password_length.unwrap_or(self.password_length),
+            password_classes: other.password_classes.unwrap_or(self.password_classes),
+            password_exclude_ambiguous: other
+                .password_exclude_ambiguous
+                .unwrap_or(self.password_exclude_ambiguous),
+            password_wordlist: other.password_wordlist.or(self.password_wordlist),
+            password_words: other.password_words.unwrap_or(self.password_words),
+            password_separator: other.password_separator.unwrap_or(self.password_separator),
+            agent_idle_timeout: other.agent_idle_timeout.unwrap_or(self.agent_idle_timeout),
+            agent_max_lifetime: other.agent_max_lifetime.unwrap_or(self.agent_max_lifetime),
+            clipboard_copy: other.clipboard_copy.or(self.clipboard_copy),
+            clipboard_paste: other.clipboard_paste.or(self.clipboard_paste),
+            clipboard_clear_after: other
+                .clipboard_clear_after
+                .unwrap_or(self.clipboard_clear_after),
+            git_auto_commit: other.git_auto_commit.unwrap_or(self.git_auto_commit),
+            hook_timeout: other.hook_timeout.unwrap_or(self.hook_timeout),
+            hook_policy: {
+                // Policies for different hooks can come from different files
+                let mut hook_policy = self.hook_policy;
+                hook_policy.extend(other.hook_policy.unwrap_or_default());
+                hook_policy
+            },
+            rules: self.rules,
+            passphrase_fd: self.passphrase_fd,
+            passphrase_file: self.passphrase_file,
+            format: self.format,
+            password_generator_from_repo: self.password_generator_from_repo,
+            clipboard_copy_from_repo: self.clipboard_copy_from_repo,
+            clipboard_paste_from_repo: self.clipboard_paste_from_repo,
+            password_generator_rule: self.password_generator_rule,
+            repo_hook_policy: self.repo_hook_policy,
         }
     }
+
+    /// Returns the rules that apply to `path`, in the order they're applied
+    pub fn rules_for(&self, path: &Path) -> Vec<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .collect()
+    }
+
+    /// Returns the configuration for a secret, with the matching rules
+    /// applied
+    pub fn for_path(&self, path: &Path) -> Config {
+        let mut result = self.clone();
+        for rule in self.rules_for(path) {
+            // The settings were checked when the config was loaded
+            let mut settings: ConfigFromFile = toml::Value::Table(rule.settings.clone())
+                .try_into()
+                .unwrap_or(EMPTY_CONFIG);
+            if rule.settings.contains_key("password-generator") {
+                result.password_generator_from_repo = rule.is_from_repo();
+                result.password_generator_rule = Some(rule.pattern.clone());
+            }
+            if rule.is_from_repo() {
+                let hook_policy = settings.hook_policy.take().unwrap_or_default();
+                result.repo_hook_policy.extend(hook_policy);
+            }
+            result = result.merge(settings);
+        }
+        result
+    }
 }
 
 pub fn default_config() -> Config {
     Config {
+        backend: Backend::Native,
         cipher: "AES-128".into(),
         key_length: 64,
         password_generator: None,
+        password_suggestions: 0,
+        password_style: PasswordStyle::Chars,
+        password_length: 20,
+        password_classes: vec![
+            CharClass::Lowercase,
+            CharClass::Uppercase,
+            CharClass::Digits,
+        ],
+        password_exclude_ambiguous: false,
+        password_wordlist: None,
+        password_words: 6,
+        password_separator: " ".into(),
+        agent_idle_timeout: 300,
+        agent_max_lifetime: 3600,
+        clipboard_copy: None,
+        clipboard_paste: None,
+        clipboard_clear_after: 45,
+        git_auto_commit: false,
+        hook_timeout: 30,
+        hook_policy: HashMap::new(),
+        rules: Vec::new(),
+        passphrase_fd: None,
+        passphrase_file: None,
+        format: Format::Text,
+        password_generator_from_repo: false,
+        clipboard_copy_from_repo: false,
+        clipboard_paste_from_repo: false,
+        password_generator_rule: None,
+        repo_hook_policy: HashMap::new(),
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
 struct ConfigFromFile {
+    pub backend: Option<Backend>,
     pub cipher: Option<String>,
     pub key_length: Option<usize>,
     pub password_generator: Option<String>,
+    pub password_suggestions: Option<usize>,
+    pub password_style: Option<PasswordStyle>,
+    pub password_length: Option<usize>,
+    pub password_classes: Option<Vec<CharClass>>,
+    pub password_exclude_ambiguous: Option<bool>,
+    pub password_wordlist: Option<PathBuf>,
+    pub password_words: Option<usize>,
+    pub password_separator: Option<String>,
+    pub agent_idle_timeout: Option<u64>,
+    pub agent_max_lifetime: Option<u64>,
+    pub clipboard_copy: Option<String>,
+    pub clipboard_paste: Option<String>,
+    pub clipboard_clear_after: Option<u64>,
+    pub git_auto_commit: Option<bool>,
+    pub hook_timeout: Option<u64>,
+    pub hook_policy: Option<HashMap<String, HookPolicy>>,
+    pub rules: Option<BTreeMap<String, toml::value::Table>>,
 }
 
 const EMPTY_CONFIG: ConfigFromFile = ConfigFromFile {
+    backend: None,
     cipher: None,
     key_length: None,
     password_generator: None,
+    password_suggestions: None,
+    password_style: None,
+    password_length: None,
+    password_classes: None,
+    password_exclude_ambiguous: None,
+    password_wordlist: None,
+    password_words: None,
+    password_separator: None,
+    agent_idle_timeout: None,
+    agent_max_lifetime: None,
+    clipboard_copy: None,
+    clipboard_paste: None,
+    clipboard_clear_after: None,
+    git_auto_commit: None,
+    hook_timeout: None,
+    hook_policy: None,
+    rules: None,
 };
 
-fn from_file(path: &Path) -> Result<ConfigFromFile, Error> {
+fn parse_rules(
+    path: &Path,
+    rules: BTreeMap<String, toml::value::Table>,
+) -> Result<Vec<Rule>, toml::de::Error> {
+    rules
+        .into_iter()
+        .map(|(pattern, settings)| {
+            let glob = glob::Pattern::new(&pattern).map_err(|e| {
+                toml::de::Error::custom(format!("invalid pattern in rules.{:?}: {}", pattern, e))
+            })?;
+            if let Some(key) = settings
+                .keys()
+                .find(|key| !RULE_SETTINGS.contains(&key.as_str()))
+            {
+                return Err(toml::de::Error::custom(format!(
+                    "{} cannot be set in rules.{:?}",
+                    key, pattern
+                )));
+            }
+            // Check the types of the values
+            toml::Value::Table(settings.clone()).try_into::<ConfigFromFile>()?;
+            Ok(Rule {
+                pattern,
+                source: path.to_path_buf(),
+                settings,
+                glob,
+            })
+        })
+        .collect()
+}
+
+// Returns the config and the rules in the file. Rules are applied in the
+// alphabetical order of their patterns.
+fn from_file(path: &Path) -> Result<(ConfigFromFile, Vec<Rule>), Error> {
+    let to_error = |e| Error {
+        path: path.to_path_buf(),
+        error: e,
+    };
     match fs::read(path) {
-        Ok(contents) => toml::from_slice(&contents).map_err(|e| Error {
-            path: path.to_path_buf(),
-            error: e,
-        }),
-        Err(_) => Ok(EMPTY_CONFIG),
+        Ok(contents) => {
+            let mut config: ConfigFromFile = toml::from_slice(&contents).map_err(to_error)?;
+            let rules =
+                parse_rules(path, config.rules.take().unwrap_or_default()).map_err(to_error)?;
+            Ok((config, rules))
+        }
+        Err(_) => Ok((EMPTY_CONFIG, Vec::new())),
     }
 }
 
+/// Returns the config file that `sala config set --repo` edits
+pub fn repo_config_path(repo_path: &Path) -> PathBuf {
+    repo_path.join(".sala/config")
+}
+
+/// Returns the config file that `sala config set --user` edits: the
+/// first one that exists, or the XDG one
+pub fn user_config_path() -> Option<PathBuf> {
+    let xdg = dirs::config_dir().map(|dir| dir.join("sala.toml"));
+    let home = dirs::home_dir().map(|dir| dir.join(".sala.toml"));
+    match (xdg, home) {
+        (Some(xdg), _) if xdg.is_file() => Some(xdg),
+        (_, Some(home)) if home.is_file() => Some(home),
+        (xdg, home) => xdg.or(home),
+    }
+}
+
+// The config files in the order they're read, latter taking precedence
+fn config_paths(repo_path: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    paths.extend(dirs::home_dir().map(|h| h.join(".sala.toml")));
+    paths.extend(dirs::config_dir().map(|h| h.join("sala.toml")));
+    paths.push(repo_config_path(repo_path));
+    paths
+}
+
 pub fn load(repo_path: &Path) -> Result<Config, Error> {
-    let home_config = dirs::home_dir()
-        .map(|h| from_file(&h.join(".sala.toml")))
-        .unwrap_or(Ok(EMPTY_CONFIG))?;
-    let xdg_config = dirs::config_dir()
-        .map(|h| from_file(&h.join("sala.toml")))
-        .unwrap_or(Ok(EMPTY_CONFIG))?;
-    let repo_config = from_file(&repo_path.join(".sala/config"))?;
-
-    let result = default_config()
-        .merge(home_config)
-        .merge(xdg_config)
-        .merge(repo_config);
+    let paths = config_paths(repo_path);
+    let mut result = default_config();
+    for path in paths.iter() {
+        let (mut config, rules) = from_file(path)?;
+        // Only the store's own config needs to be trusted
+        let from_repo = path.ends_with(".sala/config");
+        result.password_generator_from_repo = from_repo && config.password_generator.is_some();
+        result.clipboard_copy_from_repo = from_repo && config.clipboard_copy.is_some();
+        result.clipboard_paste_from_repo = from_repo && config.clipboard_paste.is_some();
+        // Anyone who can push to the store must not be able to weaken the
+        // user's own hooks
+        if from_repo {
+            let hook_policy = config.hook_policy.take().unwrap_or_default();
+            result.repo_hook_policy.extend(hook_policy);
+        }
+        result = result.merge(config);
+        result.rules.extend(rules);
+    }
+    validate(&result, &paths)?;
+
+    Ok(result)
+}
+
+// Shorter master keys would be weaker than the ciphers
+const MIN_KEY_LENGTH: usize = 16;
+
+// A year. Anything longer is more likely a typo than a real timeout.
+const MAX_TIMEOUT: u64 = 365 * 24 * 60 * 60;
+
+// Returns an error about a setting, pointing to where it's set. Settings
+// that are not in any file are reported against the last file.
+fn setting_error(paths: &[PathBuf], section: &[&str], key: &str, message: String) -> Error {
+    let found = paths.iter().rev().find_map(|path| {
+        let contents = fs::read_to_string(path).ok()?;
+        find_line(&contents, section, key).map(|line| (path, line))
+    });
+    let (path, location) = match found {
+        Some((path, line)) => (path.clone(), format!(" at line {}", line)),
+        None => (paths.last().cloned().unwrap_or_default(), String::new()),
+    };
+    Error {
+        path,
+        error: toml::de::Error::custom(format!("{} for key `{}`{}", message, key, location)),
+    }
+}
+
+fn check_timeout(value: u64, key: &str, section: &[&str], paths: &[PathBuf]) -> Result<(), Error> {
+    if value <= MAX_TIMEOUT {
+        return Ok(());
+    }
+    Err(setting_error(
+        paths,
+        section,
+        key,
+        format!(
+            "invalid value: integer `{}`, expected at most {}",
+            value, MAX_TIMEOUT
+        ),
+    ))
+}
 
+// Checks the values that are valid for their type, but not for sala
+fn validate(config: &Config, paths: &[PathBuf]) -> Result<(), Error> {
+    if config.key_length < MIN_KEY_LENGTH {
+        return Err(setting_error(
+            paths,
+            &[],
+            "key-length",
+            format!(
+                "invalid value: integer `{}`, expected at least {}",
+                config.key_length, MIN_KEY_LENGTH
+            ),
+        ));
+    }
+
+    for &(key, value) in &[
+        ("agent-idle-timeout", config.agent_idle_timeout),
+        ("agent-max-lifetime", config.agent_max_lifetime),
+        ("hook-timeout", config.hook_timeout),
+    ] {
+        check_timeout(value, key, &[], paths)?;
+    }
+    for rule in config.rules.iter() {
+        if let Some(toml::Value::Integer(value)) = rule.settings.get("hook-timeout") {
+            check_timeout(
+                *value as u64,
+                "hook-timeout",
+                &["rules", &rule.pattern],
+                std::slice::from_ref(&rule.source),
+            )?;
+        }
+    }
+
+    let supported = match gpg::crypto(&config.backend).ciphers() {
+        Some(supported) => supported,
+        None => return Ok(()),
+    };
+    let check = |cipher: &str, section: &[&str], paths: &[PathBuf]| {
+        if supported.contains(&gpg::cipher_name(cipher)) {
+            return Ok(());
+        }
+        let backend = match config.backend {
+            Backend::Native => "native",
+            Backend::Gpg => "gpg",
+        };
+        Err(setting_error(
+            paths,
+            section,
+            "cipher",
+            format!(
+                "cipher `{}` is not supported by the {} backend, expected one of {}",
+                cipher,
+                backend,
+                supported.join(", ")
+            ),
+        ))
+    };
+    check(&config.cipher, &[], paths)?;
+    for rule in config.rules.iter() {
+        if let Some(toml::Value::String(cipher)) = rule.settings.get("cipher") {
+            check(
+                cipher,
+                &["rules", &rule.pattern],
+                std::slice::from_ref(&rule.source),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The effective value of a setting, and the file it comes from. `origin`
+/// is None for the default values.
+pub struct Setting {
+    pub key: String,
+    pub value: toml::Value,
+    pub origin: Option<PathBuf>,
+}
+
+// All the settings, in the order of ConfigFromFile. hook-policy is a
+// table, and its entries are handled as separate hook-policy.NAME
+// settings.
+const SETTINGS: &[&str] = &[
+    "backend",
+    "cipher",
+    "key-length",
+    "password-generator",
+    "password-suggestions",
+    "password-style",
+    "password-length",
+    "password-classes",
+    "password-exclude-ambiguous",
+    "password-wordlist",
+    "password-words",
+    "password-separator",
+    "agent-idle-timeout",
+    "agent-max-lifetime",
+    "clipboard-copy",
+    "clipboard-paste",
+    "clipboard-clear-after",
+    "git-auto-commit",
+    "hook-timeout",
+];
+
+const TABLE_SETTINGS: &[&str] = &["hook-policy"];
+
+/// Returns true if `key` can be read and set with `sala config`
+pub fn is_setting(key: &str) -> bool {
+    match key.split_once('.') {
+        Some((table, name)) => TABLE_SETTINGS.contains(&table) && !name.is_empty(),
+        None => SETTINGS.contains(&key),
+    }
+}
+
+fn default_settings() -> toml::value::Table {
+    let config = default_config();
+    let defaults = ConfigFromFile {
+        backend: Some(config.backend),
+        cipher: Some(config.cipher),
+        key_length: Some(config.key_length),
+        password_generator: config.password_generator,
+        password_suggestions: Some(config.password_suggestions),
+        password_style: Some(config.password_style),
+        password_length: Some(config.password_length),
+        password_classes: Some(config.password_classes),
+        password_exclude_ambiguous: Some(config.password_exclude_ambiguous),
+        password_wordlist: config.password_wordlist,
+        password_words: Some(config.password_words),
+        password_separator: Some(config.password_separator),
+        agent_idle_timeout: Some(config.agent_idle_timeout),
+        agent_max_lifetime: Some(config.agent_max_lifetime),
+        clipboard_copy: config.clipboard_copy,
+        clipboard_paste: config.clipboard_paste,
+        clipboard_clear_after: Some(config.clipboard_clear_after),
+        git_auto_commit: Some(config.git_auto_commit),
+        hook_timeout: Some(config.hook_timeout),
+        hook_policy: Some(config.hook_policy),
+        rules: None,
+    };
+    match toml::Value::try_from(defaults) {
+        Ok(toml::Value::Table(table)) => table,
+        _ => toml::value::Table::new(),
+    }
+}
+
+// Flattens the tables of the settings to TABLE.NAME keys, and leaves out
+// the rules
+fn flatten(table: toml::value::Table) -> Vec<(String, toml::Value)> {
+    let mut result = Vec::new();
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(entries) if TABLE_SETTINGS.contains(&key.as_str()) => {
+                for (name, value) in entries {
+                    result.push((format!("{}.{}", key, name), value));
+                }
+            }
+            _ if key == "rules" => {}
+            value => result.push((key, value)),
+        }
+    }
+    result
+}
+
+/// Returns the effective settings, sorted by key
+pub fn settings(repo_path: &Path) -> Result<Vec<Setting>, Error> {
+    let mut settings: BTreeMap<String, (toml::Value, Option<PathBuf>)> =
+        flatten(default_settings())
+            .into_iter()
+            .map(|(key, value)| (key, (value, None)))
+            .collect();
+    for path in config_paths(repo_path) {
+        let table: toml::value::Table = match fs::read(&path) {
+            Ok(contents) => toml::from_slice(&contents).map_err(|e| Error {
+                path: path.clone(),
+                error: e,
+            })?,
+            Err(_) => continue,
+        };
+        for (key, value) in flatten(table) {
+            settings.insert(key, (value, Some(path.clone())));
+        }
+    }
+    Ok(settings
+        .into_iter()
+        .map(|(key, (value, origin))| Setting { key, value, origin })
+        .collect())
+}
+
+/// Parses a value given on the command line. Anything that isn't a valid
+/// TOML value is taken as a string.
+pub fn parse_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+fn format_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        key.to_string()
+    } else {
+        toml::Value::String(key.to_string()).to_string()
+    }
+}
+
+fn is_table_header(line: &str) -> bool {
+    line.trim_start().starts_with('[')
+}
+
+// Returns true if the line starts a `key = value` entry for `key`
+fn is_entry_for(line: &str, key: &str) -> bool {
+    let line = line.trim_start();
+    let quoted = toml::Value::String(key.to_string()).to_string();
+    [key, &quoted].iter().any(|k| {
+        line.strip_prefix(k)
+            .is_some_and(|rest| rest.trim_start().starts_with('='))
+    })
+}
+
+// Returns the keys of a [table] header line, e.g. ["rules", "legacy/*"]
+fn header_keys(line: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut value = toml::from_str::<toml::Value>(line.trim()).ok();
+    while let Some(toml::Value::Table(table)) = value {
+        if table.len() != 1 {
+            break;
+        }
+        let (key, inner) = table.into_iter().next().unwrap();
+        keys.push(key);
+        value = Some(inner);
+    }
+    keys
+}
+
+// Returns the line number of `key` in the table `section` of a config
+// file, for error messages
+fn find_line(contents: &str, section: &[&str], key: &str) -> Option<usize> {
+    let mut current = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if is_table_header(line) {
+            current = header_keys(line);
+        } else if current == section && is_entry_for(line, key) {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+// Returns the lines of the `key = value` entry between `start` and `end`.
+// The value may span several lines, e.g. an array.
+fn find_entry(lines: &[String], start: usize, end: usize, key: &str) -> Option<(usize, usize)> {
+    let i = (start..end).find(|&i| is_entry_for(&lines[i], key))?;
+    let j = (i..end)
+        .find(|&j| toml::from_str::<toml::value::Table>(&lines[i..=j].join("\n")).is_ok())
+        .unwrap_or(i);
+    Some((i, j + 1))
+}
+
+// Returns the index after the last non-blank line between `start` and
+// `end`, or `start` if there are none
+fn after_last_line(lines: &[String], start: usize, end: usize) -> usize {
+    (start..end)
+        .rev()
+        .find(|&i| !lines[i].trim().is_empty())
+        .map_or(start, |i| i + 1)
+}
+
+/// Sets `key` to `value` in the contents of a config file, changing as
+/// little of the file as possible. Returns the new contents, or an error
+/// if the result is not a valid config file.
+pub fn edit(contents: &str, key: &str, value: &toml::Value) -> Result<String, toml::de::Error> {
+    let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+    let table_start = lines
+        .iter()
+        .position(|line| is_table_header(line))
+        .unwrap_or(lines.len());
+
+    match key.split_once('.') {
+        None => {
+            let entry = format!("{} = {}", format_key(key), value);
+            match find_entry(&lines, 0, table_start, key) {
+                Some((i, j)) => {
+                    lines.splice(i..j, vec![entry]);
+                }
+                None => {
+                    let i = after_last_line(&lines, 0, table_start);
+                    lines.insert(i, entry);
+                    if lines.get(i + 1).is_some_and(|line| is_table_header(line)) {
+                        lines.insert(i + 1, String::new());
+                    }
+                }
+            }
+        }
+        Some((table, name)) => {
+            let entry = format!("{} = {}", format_key(name), value);
+            let header = lines.iter().position(|line| {
+                let line = line.trim();
+                line == format!("[{}]", table) || line == format!("[{}]", format_key(table))
+            });
+            match header {
+                Some(header) => {
+                    let end = (header + 1..lines.len())
+                        .find(|&i| is_table_header(&lines[i]))
+                        .unwrap_or(lines.len());
+                    match find_entry(&lines, header + 1, end, name) {
+                        Some((i, j)) => {
+                            lines.splice(i..j, vec![entry]);
+                        }
+                        None => {
+                            let i = after_last_line(&lines, header + 1, end);
+                            lines.insert(i, entry);
+                        }
+                    }
+                }
+                None => {
+                    if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                        lines.push(String::new());
+                    }
+                    lines.push(format!("[{}]", table));
+                    lines.push(entry);
+                }
+            }
+        }
+    }
+
+    let mut result = lines.join("\n");
+    result.push('\n');
+
+    // Check that the file is still valid, and that the value ended up
+    // where it should, e.g. not inside an inline table
+    let mut config: ConfigFromFile = toml::from_str(&result)?;
+    parse_rules(Path::new(""), config.rules.take().unwrap_or_default())?;
+    let table: toml::value::Table = toml::from_str(&result)?;
+    let found = match key.split_once('.') {
+        None => table.get(key),
+        Some((t, name)) => table.get(t).and_then(|t| t.get(name)),
+    };
+    if found != Some(value) {
+        return Err(toml::de::Error::custom(format!(
+            "cannot set {}, edit the file by hand",
+            key
+        )));
+    }
     Ok(result)
 }
@@ -0,0 +1,94 @@
+use rand::{rngs::OsRng, RngCore};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::command;
+
+// The decrypted secret is written to a private directory, preferably on
+// a tmpfs so that it never hits the disk. Editors put their swap and
+// backup files next to the file being edited, so they end up in the
+// same directory and are wiped along with it.
+struct PrivateDir {
+    path: PathBuf,
+}
+
+fn base_dirs() -> Vec<PathBuf> {
+    let mut result = vec![PathBuf::from("/dev/shm")];
+    result.extend(dirs::runtime_dir());
+    result.push(env::temp_dir());
+    result
+}
+
+impl PrivateDir {
+    fn create() -> io::Result<PrivateDir> {
+        let mut rng = OsRng::new().map_err(|e| io::Error::other(e.to_string()))?;
+        let mut last_error = io::Error::other("No directory for temporary files");
+        for base in base_dirs().iter().filter(|dir| dir.is_dir()) {
+            let path = base.join(format!("sala-{:016x}", rng.next_u64()));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(PrivateDir { path }),
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn wipe_file(path: &Path) -> io::Result<()> {
+    let length = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(&vec![0; length as usize])?;
+    file.sync_all()
+}
+
+fn wipe_dir(path: &Path) {
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => wipe_dir(&entry.path()),
+                Ok(t) if t.is_file() => {
+                    let _ = wipe_file(&entry.path());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        wipe_dir(&self.path);
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn editor_command() -> io::Result<Command> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    command::parse(&editor)
+}
+
+/// Opens $VISUAL or $EDITOR on a private copy of `contents`, named
+/// `file_name`, and returns the edited contents
+pub fn edit(file_name: &OsStr, contents: &[u8]) -> io::Result<Vec<u8>> {
+    let dir = PrivateDir::create()?;
+    let path = dir.path.join(file_name);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(contents)?;
+
+    let status = editor_command()?.arg(&path).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("Editor exited with {}", status)));
+    }
+    fs::read(&path)
+}
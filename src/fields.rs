@@ -0,0 +1,94 @@
+// A structured secret has the password on the first line, followed by
+// fields:
+//
+//   hunter2
+//   username: alice
+//   recovery-codes: 1234-5678
+//     8765-4321
+//
+// Lines starting with whitespace continue the value of the previous
+// field, with the indentation (two spaces or a tab) removed. Other lines
+// that don't look like fields are ignored, so any plain secret is a valid
+// structured secret without fields.
+
+const INDENT: &str = "  ";
+
+/// Checks whether `name` can be used as a field name
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn parse_field(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(':')?;
+    if is_valid_name(name) {
+        Some((name, value.strip_prefix(' ').unwrap_or(value)))
+    } else {
+        None
+    }
+}
+
+fn unindent(line: &str) -> Option<&str> {
+    line.strip_prefix(INDENT)
+        .or_else(|| line.strip_prefix('\t'))
+        .or_else(|| line.strip_prefix(' '))
+}
+
+/// Returns the fields of a structured secret, excluding the password
+pub fn parse(secret: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    let mut in_field = false;
+    for line in secret.lines().skip(1) {
+        if let (true, Some(continuation)) = (in_field, unindent(line)) {
+            let value = &mut fields.last_mut().unwrap().1;
+            value.push('\n');
+            value.push_str(continuation);
+        } else if let Some((name, value)) = parse_field(line) {
+            fields.push((name.to_string(), value.to_string()));
+            in_field = true;
+        } else {
+            in_field = false;
+        }
+    }
+    fields
+}
+
+/// Returns the value of a field. The password can be requested as the
+/// "password" field, unless the secret has a field of that name.
+pub fn get(secret: &str, name: &str) -> Option<String> {
+    parse(secret)
+        .into_iter()
+        .find(|(field_name, _)| field_name == name)
+        .map(|(_, value)| value)
+        .or_else(|| {
+            if name == "password" {
+                secret.lines().next().map(|line| line.to_string())
+            } else {
+                None
+            }
+        })
+}
+
+/// Formats a structured secret
+pub fn format(password: &str, fields: &[(String, String)]) -> String {
+    let mut result = password.to_string();
+    for (name, value) in fields {
+        result.push('\n');
+        result.push_str(name);
+        result.push(':');
+        for (i, line) in value.lines().enumerate() {
+            if i == 0 {
+                if !line.is_empty() {
+                    result.push(' ');
+                }
+            } else {
+                result.push('\n');
+                result.push_str(INDENT);
+            }
+            result.push_str(line);
+        }
+    }
+    result
+}
@@ -0,0 +1,125 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::gpg::{self, Crypto};
+use crate::Problem;
+
+const ARMOR_HEADER: &[u8] = b"-----BEGIN PGP MESSAGE-----";
+
+struct Checker<'a> {
+    repo_path: &'a Path,
+    crypto: &'a dyn Crypto,
+    master_key: &'a [u8],
+    checked: usize,
+    problems: Vec<(PathBuf, Problem)>,
+}
+
+impl<'a> Checker<'a> {
+    fn check_permissions(&mut self, path: &Path, metadata: &fs::Metadata) {
+        // Anyone who can write to the store can replace the secrets
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o002 != 0 {
+            self.problems
+                .push((path.to_path_buf(), Problem::LoosePermissions(mode)));
+        }
+    }
+
+    fn check_file(&mut self, path: &Path) -> io::Result<()> {
+        let message = fs::read(self.repo_path.join(path))?;
+        self.checked += 1;
+        let armored = message
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .is_some_and(|start| message[start..].starts_with(ARMOR_HEADER));
+        if !armored {
+            self.problems
+                .push((path.to_path_buf(), Problem::NotArmored));
+        } else if let Err(err) = self.crypto.decrypt(&message, self.master_key) {
+            let message = match err {
+                gpg::GpgError::IOError(err) => err.to_string(),
+                gpg::GpgError::OperationFailed(message)
+                | gpg::GpgError::BackendFailed(message)
+                | gpg::GpgError::Unsupported(message) => message,
+            };
+            self.problems
+                .push((path.to_path_buf(), Problem::DecryptionFailed(message)));
+        }
+        Ok(())
+    }
+
+    fn check_dir(&mut self, dir: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(self.repo_path.join(dir))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        if entries.is_empty() {
+            self.problems
+                .push((dir.to_path_buf(), Problem::EmptyDirectory));
+            return Ok(());
+        }
+        entries.sort();
+
+        for name in entries {
+            let path = dir.join(&name);
+            let name = name.to_string_lossy();
+            if name.ends_with(".tmp") {
+                self.problems.push((path, Problem::LeftoverTemporaryFile));
+                continue;
+            }
+            // .sala and .git are not part of the secrets
+            if name.starts_with('.') {
+                continue;
+            }
+            let metadata = fs::metadata(self.repo_path.join(&path))?;
+            self.check_permissions(&path, &metadata);
+            if metadata.is_dir() {
+                self.check_dir(&path)?;
+            } else if metadata.is_file() {
+                self.check_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_sala_dir(&mut self) -> io::Result<()> {
+        let sala_dir = Path::new(".sala");
+        for entry in fs::read_dir(self.repo_path.join(sala_dir))? {
+            let name = entry?.file_name();
+            if name.to_string_lossy().ends_with(".tmp") {
+                self.problems
+                    .push((sala_dir.join(name), Problem::LeftoverTemporaryFile));
+            }
+        }
+        for path in [sala_dir, &sala_dir.join("key")].iter() {
+            let metadata = fs::metadata(self.repo_path.join(path))?;
+            self.check_permissions(path, &metadata);
+        }
+        let trash = sala_dir.join("trash");
+        if self.repo_path.join(&trash).is_dir() {
+            self.check_dir(&trash)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decrypts every secret in the store, including the trash, and looks for
+/// files that don't belong there. Returns the number of secrets checked
+/// and the problems found.
+pub fn check(
+    repo_path: &Path,
+    crypto: &dyn Crypto,
+    master_key: &[u8],
+) -> io::Result<(usize, Vec<(PathBuf, Problem)>)> {
+    let mut checker = Checker {
+        repo_path,
+        crypto,
+        master_key,
+        checked: 0,
+        problems: Vec::new(),
+    };
+    checker.check_sala_dir()?;
+    checker.check_dir(Path::new(""))?;
+    checker.problems.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok((checker.checked, checker.problems))
+}
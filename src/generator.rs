@@ -0,0 +1,94 @@
+use crate::config::{CharClass, Config, PasswordStyle};
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use std::fs;
+use std::io;
+
+// Characters that are easy to confuse with each other when read or typed
+const AMBIGUOUS: &str = "0O1Il|`'\"";
+
+fn class_chars(class: CharClass) -> &'static str {
+    match class {
+        CharClass::Lowercase => "abcdefghijklmnopqrstuvwxyz",
+        CharClass::Uppercase => "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+        CharClass::Digits => "0123456789",
+        CharClass::Symbols => "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~",
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn generate_chars(config: &Config, rng: &mut OsRng) -> io::Result<String> {
+    let mut classes: Vec<Vec<char>> = Vec::new();
+    for &class in &config.password_classes {
+        let chars: Vec<char> = class_chars(class)
+            .chars()
+            .filter(|c| !config.password_exclude_ambiguous || !AMBIGUOUS.contains(*c))
+            .collect();
+        if !classes.contains(&chars) {
+            classes.push(chars);
+        }
+    }
+    if classes.is_empty() {
+        return Err(invalid("password-classes is empty"));
+    }
+    if config.password_length < classes.len() {
+        return Err(invalid(
+            "password-length is too short to include all password-classes",
+        ));
+    }
+    let all: Vec<char> = classes.concat();
+
+    // Draw until every class is represented, so that each character stays
+    // uniformly distributed
+    loop {
+        let password: Vec<char> = (0..config.password_length)
+            .map(|_| *all.choose(rng).unwrap())
+            .collect();
+        if classes
+            .iter()
+            .all(|chars| password.iter().any(|c| chars.contains(c)))
+        {
+            return Ok(password.into_iter().collect());
+        }
+    }
+}
+
+fn generate_words(config: &Config, rng: &mut OsRng) -> io::Result<String> {
+    let wordlist = config
+        .password_wordlist
+        .as_ref()
+        .ok_or_else(|| invalid("password-wordlist is not set"))?;
+    let contents = fs::read_to_string(wordlist)?;
+
+    // Accept both plain lists with one word per line and diceware lists
+    // where each word is preceded by its dice roll
+    let mut words: Vec<&str> = contents
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .collect();
+    words.sort_unstable();
+    words.dedup();
+    if words.len() < 2 {
+        return Err(invalid("password-wordlist has too few words"));
+    }
+    if config.password_words == 0 {
+        return Err(invalid("password-words must be at least 1"));
+    }
+
+    Ok((0..config.password_words)
+        .map(|_| *words.choose(rng).unwrap())
+        .collect::<Vec<&str>>()
+        .join(&config.password_separator))
+}
+
+/// Generates a password according to the password policy of the config
+pub fn generate(config: &Config) -> io::Result<String> {
+    let mut rng = OsRng::new().map_err(|e| io::Error::other(e.to_string()))?;
+    match config.password_style {
+        PasswordStyle::Chars => generate_chars(config, &mut rng),
+        PasswordStyle::Words => generate_words(config, &mut rng),
+    }
+}
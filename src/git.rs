@@ -0,0 +1,97 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+// Encrypted secrets can't be diffed or merged line by line. A textual
+// merge would leave conflict markers in the middle of a secret.
+const GITATTRIBUTES: &str = "\
+* -diff -merge
+.gitattributes diff merge
+.sala/config diff merge
+";
+
+fn git(repo_path: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.current_dir(repo_path);
+    command
+}
+
+fn failure(what: &str, output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim_end();
+    if stderr.is_empty() {
+        format!("git {} failed with {}", what, output.status)
+    } else {
+        format!("git {} failed with {}: {}", what, output.status, stderr)
+    }
+}
+
+/// Returns true if the store is in a git work tree
+pub fn is_work_tree(repo_path: &Path) -> bool {
+    git(repo_path)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| output.status.success() && output.stdout.starts_with(b"true"))
+        .unwrap_or(false)
+}
+
+/// Runs git in the store with the given arguments, inheriting stdio
+pub fn run(repo_path: &Path, args: &[&OsStr]) -> io::Result<ExitStatus> {
+    git(repo_path).args(args).status()
+}
+
+/// Creates a git repository for the store, with attributes suitable for
+/// encrypted files
+pub fn init(repo_path: &Path) -> Result<(), String> {
+    let output = git(repo_path)
+        .args(["init", "--quiet"])
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        return Err(failure("init", &output));
+    }
+    let path = repo_path.join(".gitattributes");
+    if !path.exists() {
+        fs::write(&path, GITATTRIBUTES).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Commits the changes to `paths` and nothing else. Returns a warning if
+/// committing fails.
+pub fn commit(repo_path: &Path, paths: &[&Path], message: &str) -> Option<String> {
+    let result = git(repo_path)
+        .args(["add", "--all", "--"])
+        .args(paths)
+        .output()
+        .and_then(|output| {
+            if !output.status.success() {
+                return Ok(Some(failure("add", &output)));
+            }
+            // Nothing to commit, e.g. because the paths are ignored
+            let unchanged = git(repo_path)
+                .args(["diff", "--cached", "--quiet", "--"])
+                .args(paths)
+                .status()?
+                .success();
+            if unchanged {
+                return Ok(None);
+            }
+            let output = git(repo_path)
+                .args(["commit", "--quiet", "--message", message, "--"])
+                .args(paths)
+                .output()?;
+            if output.status.success() {
+                Ok(None)
+            } else {
+                Ok(Some(failure("commit", &output)))
+            }
+        });
+    match result {
+        Ok(warning) => warning,
+        Err(err) => Some(format!("Cannot run git: {}", err)),
+    }
+}
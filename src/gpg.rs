@@ -7,118 +7,185 @@ use std::fs::{self, File};
 use std::io;
 use std::io::Write;
 use std::os::unix::io::FromRawFd;
-use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::config::Backend;
+use crate::openpgp::Native;
 
 #[derive(Debug)]
 pub enum GpgError {
     IOError(io::Error),
     OperationFailed(String),
+    BackendFailed(String),
+    /// The message uses a feature that the backend doesn't implement
+    Unsupported(String),
 }
 
-fn nix_err(err: nix::Error) -> GpgError {
-    if let nix::Error::Sys(err_no) = err {
-        GpgError::IOError(io::Error::from(err_no))
-    } else {
-        panic!("unexpected nix error type: {:?}", err)
-    }
-}
+pub trait Crypto {
+    fn decrypt(&self, message: &[u8], key: &[u8]) -> Result<Vec<u8>, GpgError>;
+    fn encrypt(&self, data: &[u8], key: &[u8], cipher: &str) -> Result<Vec<u8>, GpgError>;
 
-fn gpg_err(msg: &[u8]) -> GpgError {
-    GpgError::OperationFailed(String::from_utf8_lossy(msg).to_string())
+    /// Returns the names of the supported ciphers, normalized with
+    /// `cipher_name`, or None if they can't be found out
+    fn ciphers(&self) -> Option<Vec<String>>;
 }
 
-pub fn decrypt(path: &Path, key: &[u8]) -> Result<Vec<u8>, GpgError> {
-    let (passphrase_read_fd, passphrase_write_fd) = unistd::pipe().map_err(nix_err)?;
-    fcntl::fcntl(passphrase_write_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_err)?;
-
-    let gpg = Command::new("gpg")
-        .arg("--batch")
-        .arg("--no-tty")
-        .arg("--armor")
-        .arg("--decrypt")
-        .arg("--passphrase-fd")
-        .arg(passphrase_read_fd.to_string())
-        .arg("--")
-        .arg(path.as_os_str())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(GpgError::IOError)?;
-
-    unistd::close(passphrase_read_fd).map_err(nix_err)?;
-    unsafe {
-        let mut stream = UnixStream::from_raw_fd(passphrase_write_fd);
-        stream.write_all(key).map_err(GpgError::IOError)?;
+/// Normalizes a cipher name. gpg accepts many names for the same cipher,
+/// e.g. "AES-128", "aes128" and "AES" all mean the same thing.
+pub fn cipher_name(name: &str) -> String {
+    let normalized = name.to_uppercase().replace('-', "");
+    match normalized.as_str() {
+        "AES" => "AES128".to_string(),
+        "CAMELLIA" => "CAMELLIA128".to_string(),
+        _ => normalized,
     }
+}
 
-    let output = gpg.wait_with_output().map_err(GpgError::IOError)?;
-    if output.status.success() {
-        Ok(output.stdout)
-    } else {
-        Err(gpg_err(&output.stderr))
+pub fn crypto(backend: &Backend) -> Box<dyn Crypto> {
+    match backend {
+        Backend::Native => Box::new(Native),
+        Backend::Gpg => Box::new(GnuPG),
     }
 }
 
-pub fn encrypt(data: &str, key: &[u8], target: &Path, cipher: &str) -> Result<(), GpgError> {
+pub fn decrypt(crypto: &dyn Crypto, path: &Path, key: &[u8]) -> Result<Vec<u8>, GpgError> {
+    let message = fs::read(path).map_err(GpgError::IOError)?;
+    crypto.decrypt(&message, key)
+}
+
+pub fn encrypt(
+    crypto: &dyn Crypto,
+    data: &[u8],
+    key: &[u8],
+    target: &Path,
+    cipher: &str,
+) -> Result<(), GpgError> {
+    let message = crypto.encrypt(data, key, cipher)?;
+
     let mut target_tmp = target.as_os_str().to_os_string();
     target_tmp.push(".tmp");
 
-    let target_file = File::create(&target_tmp).map_err(GpgError::IOError)?;
-    match gpg_encrypt_impl(data, key, target_file, cipher) {
+    // The file is synced before it replaces the old one, and the directory
+    // after that, so that a crash leaves either the old or the new file
+    let mut target_file = File::create(&target_tmp).map_err(GpgError::IOError)?;
+    match target_file
+        .write_all(&message)
+        .and_then(|_| target_file.sync_all())
+    {
         Ok(_) => {
             fs::rename(&target_tmp, target).map_err(GpgError::IOError)?;
-            Ok(())
+            sync_parent(target).map_err(GpgError::IOError)
         }
         Err(err) => {
             fs::remove_file(&target_tmp).map_err(GpgError::IOError)?;
-            Err(err)
+            Err(GpgError::IOError(err))
         }
     }
 }
 
-fn gpg_encrypt_impl<T: Into<Stdio>>(
-    data: &str,
-    key: &[u8],
-    outfile: T,
-    cipher: &str,
-) -> Result<(), GpgError> {
-    let (passphrase_read_fd, passphrase_write_fd) = unistd::pipe().map_err(nix_err)?;
-    fcntl::fcntl(passphrase_write_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(nix_err)?;
-
-    let mut gpg = Command::new("gpg")
-        .arg("--batch")
-        .arg("--no-tty")
-        .arg("--armor")
-        .arg("--symmetric")
-        .arg("--cipher-algo")
-        .arg(cipher)
-        .arg("--passphrase-fd")
-        .arg(passphrase_read_fd.to_string())
-        .stdin(Stdio::piped())
-        .stdout(outfile)
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(GpgError::IOError)?;
-
-    unistd::close(passphrase_read_fd).map_err(nix_err)?;
-    unsafe {
-        let mut stream = UnixStream::from_raw_fd(passphrase_write_fd);
-        stream.write_all(key).map_err(GpgError::IOError)?;
-    }
-    {
-        gpg.stdin
-            .as_mut()
-            .unwrap()
-            .write_all(data.as_bytes())
-            .map_err(GpgError::IOError)?;
+/// Makes a rename or removal of `path` durable
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if dir != Path::new("") => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
     }
+}
 
-    let output = gpg.wait_with_output().map_err(GpgError::IOError)?;
-    if output.status.success() {
-        Ok(())
+fn nix_err(err: nix::Error) -> GpgError {
+    if let nix::Error::Sys(err_no) = err {
+        GpgError::IOError(io::Error::from(err_no))
     } else {
-        Err(gpg_err(&output.stderr))
+        GpgError::OperationFailed(err.to_string())
+    }
+}
+
+fn gpg_err(msg: &[u8]) -> GpgError {
+    GpgError::OperationFailed(String::from_utf8_lossy(msg).trim_end().to_string())
+}
+
+/// Runs the gpg binary for every operation
+pub struct GnuPG;
+
+impl GnuPG {
+    fn run(&self, args: &[&str], input: &[u8], key: &[u8]) -> Result<Vec<u8>, GpgError> {
+        let (passphrase_read_fd, passphrase_write_fd) = unistd::pipe().map_err(nix_err)?;
+        fcntl::fcntl(passphrase_write_fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(nix_err)?;
+
+        let mut gpg = Command::new("gpg")
+            .arg("--batch")
+            .arg("--no-tty")
+            .arg("--armor")
+            .args(args)
+            .arg("--passphrase-fd")
+            .arg(passphrase_read_fd.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| GpgError::BackendFailed(format!("cannot run gpg: {}", e)))?;
+
+        unistd::close(passphrase_read_fd).map_err(nix_err)?;
+        let mut passphrase = unsafe { File::from_raw_fd(passphrase_write_fd) };
+        let mut stdin = gpg.stdin.take().unwrap();
+
+        // gpg starts writing its output before it has read all of its
+        // input, so the input is written while the output is being read.
+        // Otherwise both would block once the pipe buffers are full.
+        let (written, output) = thread::scope(|scope| {
+            let writer = scope.spawn(move || {
+                passphrase.write_all(key)?;
+                drop(passphrase);
+                stdin.write_all(input)
+            });
+            let output = gpg.wait_with_output();
+            (writer.join().unwrap(), output)
+        });
+
+        let output = output.map_err(GpgError::IOError)?;
+        if !output.status.success() {
+            // gpg may exit without reading all of its input, so the
+            // failure to write it is not the interesting error
+            return Err(gpg_err(&output.stderr));
+        }
+        written.map_err(GpgError::IOError)?;
+        Ok(output.stdout)
+    }
+}
+
+impl Crypto for GnuPG {
+    fn decrypt(&self, message: &[u8], key: &[u8]) -> Result<Vec<u8>, GpgError> {
+        self.run(&["--decrypt"], message, key)
+    }
+
+    fn encrypt(&self, data: &[u8], key: &[u8], cipher: &str) -> Result<Vec<u8>, GpgError> {
+        self.run(&["--symmetric", "--cipher-algo", cipher], data, key)
+    }
+
+    // The list looks like this, possibly wrapped to several lines:
+    //
+    // Cipher: IDEA, 3DES, CAST5, BLOWFISH, AES, AES192, AES256, TWOFISH,
+    //         CAMELLIA128, CAMELLIA192, CAMELLIA256
+    fn ciphers(&self) -> Option<Vec<String>> {
+        let output = Command::new("gpg")
+            .arg("--version")
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut lines = output
+            .lines()
+            .skip_while(|line| !line.starts_with("Cipher:"));
+        let mut list = lines.next()?.trim_start_matches("Cipher:").to_string();
+        list.extend(lines.take_while(|line| line.starts_with(char::is_whitespace)));
+        Some(
+            list.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(cipher_name)
+                .collect(),
+        )
     }
 }
@@ -1,81 +1,360 @@
-use dirs;
-
+use serde_json::{Map, Value};
 use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::str;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, HookPolicy};
+use crate::trust::{self, Trust};
 
 pub enum Hook {
-    PostGet(String),
+    PreUnlock,
+    PreGet,
+    PostGet(Vec<u8>),
+    PreSet,
     PostSet,
+    PreMove(PathBuf),
+    PostMove(PathBuf),
+    PreRemove,
+    PostRemove,
+    PostInit,
 }
 
 fn hook_name(hook: &Hook) -> &'static str {
     match hook {
+        Hook::PreUnlock => "pre-unlock",
+        Hook::PreGet => "pre-get",
         Hook::PostGet(_) => "post-get",
+        Hook::PreSet => "pre-set",
         Hook::PostSet => "post-set",
+        Hook::PreMove(_) => "pre-move",
+        Hook::PostMove(_) => "post-move",
+        Hook::PreRemove => "pre-remove",
+        Hook::PostRemove => "post-remove",
+        Hook::PostInit => "post-init",
     }
 }
 
-fn hook_args(hook: &Hook, path: &Path) -> Vec<OsString> {
+// An empty path means that the hook runs for several secrets at once
+fn has_path(hook: &Hook, path: &Path) -> bool {
+    !matches!(hook, Hook::PreUnlock | Hook::PostInit) && !path.as_os_str().is_empty()
+}
+
+fn hook_target(hook: &Hook) -> Option<&Path> {
     match hook {
-        Hook::PostGet(secret) => vec![OsString::from(path), OsString::from(secret)],
-        Hook::PostSet => vec![OsString::from(path)],
+        Hook::PreMove(target) | Hook::PostMove(target) => Some(target),
+        _ => None,
+    }
+}
+
+// Only the paths are passed as arguments, as anyone on the machine can
+// read the command line of a process
+fn hook_args(hook: &Hook, path: &Path) -> Vec<OsString> {
+    let mut args = Vec::new();
+    if has_path(hook, path) {
+        args.push(OsString::from(path));
+    }
+    if let Some(target) = hook_target(hook) {
+        args.push(OsString::from(target));
+    }
+    args
+}
+
+fn hook_env(hook: &Hook, repo_root: &Path, path: &Path) -> Vec<(&'static str, OsString)> {
+    let mut env = vec![
+        ("SALA_EVENT", OsString::from(hook_name(hook))),
+        ("SALA_REPO", OsString::from(repo_root)),
+    ];
+    if has_path(hook, path) {
+        env.push(("SALA_PATH", OsString::from(path)));
+    }
+    if let Some(target) = hook_target(hook) {
+        env.push(("SALA_TARGET", OsString::from(target)));
     }
+    env
 }
 
-fn hook_warning(hook_path: &Path, exit_status: &ExitStatus) -> String {
-    let status = match exit_status.code() {
-        Some(s) => s.to_string(),
-        None => "(signaled)".to_string(),
+// The secret is only ever passed on stdin
+fn hook_input(hook: &Hook, repo_root: &Path, path: &Path) -> String {
+    let mut input = Map::new();
+    input.insert("event".to_string(), Value::from(hook_name(hook)));
+    input.insert("repo".to_string(), Value::from(repo_root.to_string_lossy()));
+    if has_path(hook, path) {
+        input.insert("path".to_string(), Value::from(path.to_string_lossy()));
+    }
+    if let Some(target) = hook_target(hook) {
+        input.insert("target".to_string(), Value::from(target.to_string_lossy()));
+    }
+    if let Hook::PostGet(secret) = hook {
+        match str::from_utf8(secret) {
+            Ok(secret) => input.insert("secret".to_string(), Value::from(secret)),
+            Err(_) => input.insert(
+                "secret_base64".to_string(),
+                Value::from(base64::encode(secret)),
+            ),
+        };
+    }
+    Value::Object(input).to_string()
+}
+
+fn hook_warning(hook_path: &Path, exit_status: Option<ExitStatus>, timeout: u64) -> String {
+    match exit_status {
+        Some(exit_status) => {
+            let status = match exit_status.code() {
+                Some(s) => s.to_string(),
+                None => "(signaled)".to_string(),
+            };
+            format!(
+                "Hook {hook_path:?} failed with status {status}",
+                hook_path = hook_path,
+                status = status
+            )
+        }
+        None => format!(
+            "Hook {hook_path:?} timed out after {timeout} seconds",
+            hook_path = hook_path,
+            timeout = timeout
+        ),
+    }
+}
+
+// Returns None if the hook was killed because it ran for too long
+fn wait(child: &mut Child, timeout: u64) -> io::Result<Option<ExitStatus>> {
+    // A timeout too large to represent is the same as none
+    let deadline = match Instant::now().checked_add(Duration::from_secs(timeout)) {
+        Some(deadline) if timeout > 0 => deadline,
+        _ => return child.wait().map(Some),
     };
-    return format!(
-        "Hook {hook_path:?} failed with status {status}",
-        hook_path = hook_path,
-        status = status
-    );
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
 }
 
-fn run_hook_script(repo_path: &Path, hook_dir: &Path, hook: &Hook, path: &Path) -> Option<String> {
-    let hook_path = hook_dir.join(hook_name(hook));
+fn run_hook_script(
+    repo_path: &Path,
+    hook_path: &Path,
+    hook: &Hook,
+    path: &Path,
+    timeout: u64,
+) -> Option<String> {
+    let repo_root = repo_path
+        .canonicalize()
+        .unwrap_or_else(|_| repo_path.to_path_buf());
 
-    let result = Command::new(&hook_path)
+    // Our stdout may be reserved for machine-readable output, so the
+    // output of hooks goes to stderr
+    let mut child = match Command::new(hook_path)
         .current_dir(repo_path)
         .args(hook_args(hook, path))
+        .envs(hook_env(hook, &repo_root, path))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::from(io::stderr()))
+        .stderr(Stdio::piped())
         .spawn()
-        .and_then(|mut c| c.wait());
-
-    match result {
+    {
+        Ok(child) => child,
         Err(_) => {
             // Command not found or not executable, this is fine!
-            None
+            return None;
         }
-        Ok(exit_status) => {
-            if exit_status.success() {
-                None
-            } else {
-                Some(hook_warning(&hook_path, &exit_status))
-            }
+    };
+
+    // The hook may not read its input, or keep its stderr open in a
+    // background process, so neither is allowed to block us
+    let input = hook_input(hook, &repo_root, path);
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = stderr.read_to_end(&mut output);
+        let _ = sender.send(output);
+    });
+
+    let exit_status = match wait(&mut child, timeout) {
+        Ok(Some(exit_status)) if exit_status.success() => return None,
+        Ok(exit_status) => exit_status,
+        Err(_) => return None,
+    };
+    let mut warning = hook_warning(hook_path, exit_status, timeout);
+    if let Ok(output) = receiver.recv_timeout(Duration::from_millis(100)) {
+        for line in String::from_utf8_lossy(&output).lines() {
+            warning.push_str("\n  ");
+            warning.push_str(line);
         }
     }
+    Some(warning)
 }
 
 fn get_hook_dirs(repo_path: &Path) -> Vec<PathBuf> {
     let mut result: Vec<PathBuf> = Vec::new();
 
     result.push(repo_path.join(".sala"));
-    dirs::config_dir().map(|dir| result.push(dir.join("sala")));
+    if let Some(dir) = dirs::config_dir() {
+        result.push(dir.join("sala"))
+    }
+
+    result
+}
+
+const HOOK_NAMES: &[&str] = &[
+    "pre-unlock",
+    "pre-get",
+    "post-get",
+    "pre-set",
+    "post-set",
+    "pre-move",
+    "post-move",
+    "pre-remove",
+    "post-remove",
+    "post-init",
+];
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+// Returns the hook scripts for a hook name in a hook directory, in the
+// order they're run, and the keys that their failure policy is looked up
+// with
+fn get_dir_scripts(dir: &Path, name: &str) -> Vec<(PathBuf, String)> {
+    let mut result = vec![(dir.join(name), name.to_string())];
+
+    let hooks_d = dir.join("hooks.d").join(name);
+    if let Ok(entries) = fs::read_dir(&hooks_d) {
+        let mut entries = entries
+            .filter_map(|entry| entry.ok().map(|e| e.file_name()))
+            .filter(|file_name| !file_name.to_string_lossy().starts_with('.'))
+            .collect::<Vec<_>>();
+        entries.sort();
+        for file_name in entries {
+            let key = format!("{}/{}", name, file_name.to_string_lossy());
+            result.push((hooks_d.join(file_name), key));
+        }
+    }
 
     result
+        .into_iter()
+        .filter(|(path, _)| is_executable(path))
+        .collect()
 }
 
-pub fn run_hook(repo_path: &Path, path: &Path, hook: Hook) -> Vec<String> {
-    let dirs = get_hook_dirs(repo_path);
+/// Returns all hook scripts in the store's .sala directory
+pub fn get_local_scripts(repo_path: &Path) -> Vec<PathBuf> {
+    HOOK_NAMES
+        .iter()
+        .flat_map(|name| get_dir_scripts(&repo_path.join(".sala"), name))
+        .map(|(path, _)| path)
+        .collect()
+}
 
+// Hooks in the store only run if the user has trusted them
+fn check_trust(repo_path: &Path, hook_dir: &Path, hook_path: &Path) -> Option<String> {
+    if hook_dir != repo_path.join(".sala") {
+        return None;
+    }
+    let contents = fs::read(hook_path).unwrap_or_default();
+    let reason = match trust::check(&trust::file_key(hook_path), &contents) {
+        Trust::Trusted => return None,
+        Trust::Untrusted => "is not trusted",
+        Trust::Changed => "has changed since it was trusted",
+    };
+    Some(format!(
+        "Hook {hook_path:?} {reason}, run `sala trust' to review it",
+        hook_path = hook_path,
+        reason = reason
+    ))
+}
+
+fn is_pre_hook(hook: &Hook) -> bool {
+    hook_name(hook).starts_with("pre-")
+}
+
+// By default, pre-* hooks abort and post-* hooks warn. The store's config
+// can only set the policy of the store's own hooks.
+fn hook_policy(config: &Config, hook: &Hook, key: &str, in_repo: bool) -> HookPolicy {
+    let get = |key: &str| {
+        let repo_policy = if in_repo {
+            config.repo_hook_policy.get(key)
+        } else {
+            None
+        };
+        repo_policy.or_else(|| config.hook_policy.get(key))
+    };
+    get(key)
+        .or_else(|| get(hook_name(hook)))
+        .cloned()
+        .unwrap_or(if is_pre_hook(hook) {
+            HookPolicy::Abort
+        } else {
+            HookPolicy::Warn
+        })
+}
+
+// Runs all the hook scripts for an event. Returns the warnings from the
+// failed hooks, or the failure of the first hook whose policy is to abort.
+fn run_hook_scripts(
+    repo_path: &Path,
+    path: &Path,
+    hook: &Hook,
+    config: &Config,
+) -> Result<Vec<String>, String> {
     let mut warnings = Vec::new();
-    for dir in dirs.iter() {
-        run_hook_script(repo_path, &dir, &hook, path).map(|w| warnings.push(w));
+    for dir in get_hook_dirs(repo_path) {
+        let in_repo = dir == repo_path.join(".sala");
+        for (hook_path, key) in get_dir_scripts(&dir, hook_name(hook)) {
+            let failure = check_trust(repo_path, &dir, &hook_path).or_else(|| {
+                run_hook_script(repo_path, &hook_path, hook, path, config.hook_timeout)
+            });
+            if let Some(failure) = failure {
+                match hook_policy(config, hook, &key, in_repo) {
+                    HookPolicy::Abort => return Err(failure),
+                    HookPolicy::Warn => warnings.push(failure),
+                }
+            }
+        }
     }
+    Ok(warnings)
+}
+
+/// Runs a post-* hook. Returns the warnings from the failed hooks, or the
+/// failure of a hook whose policy is to abort.
+pub fn run_hook(
+    repo_path: &Path,
+    path: &Path,
+    hook: Hook,
+    config: &Config,
+) -> Result<Vec<String>, String> {
+    run_hook_scripts(repo_path, path, &hook, config)
+}
 
-    warnings
+/// Runs a pre-* hook. The first hook that fails vetoes the operation, and
+/// its failure is returned. Like with run_hook, the warnings from hooks
+/// whose policy is to warn are returned, to be shown with the output.
+pub fn run_pre_hook(
+    repo_path: &Path,
+    path: &Path,
+    hook: Hook,
+    config: &Config,
+) -> Result<Vec<String>, String> {
+    run_hook_scripts(repo_path, path, &hook, config)
 }
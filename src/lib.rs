@@ -1,39 +1,397 @@
+mod agent;
+mod clipboard;
+mod command;
 pub mod config;
+mod editor;
+mod fields;
+mod fsck;
+mod generator;
+mod git;
 mod gpg;
 mod hooks;
+mod openpgp;
+mod trust;
+mod walk;
 
 extern crate shell_words;
+use nix::unistd;
 use rand::{rngs::OsRng, RngCore};
-use rpassword;
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use regex::RegexBuilder;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Component, Path, PathBuf};
 use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use self::config::Config;
-use self::hooks::{run_hook, Hook};
+use self::config::{Config, Format, Rule, Setting};
+use self::gpg::{Crypto, GpgError};
+use self::hooks::{run_hook, run_pre_hook, Hook};
+use self::trust::Trust;
+
+// Messages about the progress go to stdout, unless it's reserved for
+// machine-readable output
+macro_rules! message {
+    ($config:expr, $($arg:tt)*) => {
+        if $config.format == Format::Json {
+            eprint!($($arg)*);
+        } else {
+            print!($($arg)*);
+        }
+    };
+}
 
 pub enum Output {
-    Get(PathBuf, Vec<u8>, bool, Vec<String>),
+    Get(PathBuf, Option<String>, Vec<u8>, bool, Vec<String>),
+    GetMany(Vec<Result<(PathBuf, Vec<u8>), Error>>, bool, Vec<String>),
+    Copied(PathBuf, u64, bool, Vec<String>),
     Put(Vec<String>),
+    Unchanged(PathBuf, bool, Vec<String>),
+    List(PathBuf, Vec<PathBuf>, bool),
+    Matches(Vec<(PathBuf, Vec<String>)>, bool, Vec<String>),
+    Checked(usize, Vec<(PathBuf, Problem)>, Vec<String>),
+    Rules(Option<PathBuf>, Vec<Rule>),
+    Settings(Vec<Setting>),
+    Setting(String, Option<Setting>),
     NoOutput,
 }
 
+impl Output {
+    /// Returns the exit status of the process. It's non-zero if some of
+    /// the work failed, e.g. some of the secrets could not be read,
+    /// `sala fsck` found problems (8), or `sala config get` found no value
+    /// (1).
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GetMany(results, _, _) => results
+                .iter()
+                .find_map(|result| result.as_ref().err())
+                .map_or(0, |error| error.exit_code()),
+            Checked(_, problems, _) if !problems.is_empty() => 8,
+            Setting(_, None) => 1,
+            _ => 0,
+        }
+    }
+}
+
+/// A problem found by `sala fsck`
+pub enum Problem {
+    DecryptionFailed(String),
+    EmptyDirectory,
+    LeftoverTemporaryFile,
+    LoosePermissions(u32),
+    NotArmored,
+}
+
+impl Problem {
+    /// Returns a stable, machine-readable code for the problem
+    pub fn code(&self) -> &'static str {
+        match self {
+            Problem::DecryptionFailed(_) => "decryption_failed",
+            Problem::EmptyDirectory => "empty_directory",
+            Problem::LeftoverTemporaryFile => "leftover_temporary_file",
+            Problem::LoosePermissions(_) => "loose_permissions",
+            Problem::NotArmored => "not_armored",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::DecryptionFailed(message) => write!(f, "Unable to decrypt: {}", message),
+            Problem::EmptyDirectory => write!(f, "Empty directory"),
+            Problem::LeftoverTemporaryFile => write!(f, "Leftover temporary file"),
+            Problem::LoosePermissions(mode) => {
+                write!(f, "Writable by others (mode {:o})", mode)
+            }
+            Problem::NotArmored => write!(f, "Not an armored OpenPGP message"),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum Error {
+    AgentAlreadyRunning,
+    AgentFailed(String),
     AlreadyInitialized,
+    BackendFailed(String),
+    CannotCopyMany,
     CannotInitRepo,
+    ClipboardFailed(String),
     FileDoesNotExist(PathBuf),
+    GitFailed(String),
+    HookFailed(String),
+    InputFailed(String),
     InputsDidntMatch,
     NoRepo,
+    TargetExists(PathBuf),
     TargetIsDirectory(PathBuf),
     CannotCreateDirectory(PathBuf),
+    DecryptionFailed(PathBuf, String),
+    EditorFailed(String),
+    EncryptionFailed(PathBuf, String),
+    FieldDoesNotExist(PathBuf, String),
+    InvalidConfig(PathBuf, String),
+    InvalidFieldName(String),
+    InvalidPath(PathBuf),
+    InvalidPattern(String),
+    IoFailed(PathBuf, io::Error),
+    NoAgent,
+    NoClipboard,
+    NoRekeyInProgress,
+    PasswordGenerationFailed(String),
+    RekeyCannotAbort,
+    RekeyInProgress,
+    UnknownSetting(String),
     UnlockFailed,
+    Untrusted(String),
     Usage,
 }
 
+pub enum RekeyMode {
+    Start,
+    Continue,
+    Abort,
+}
+
+pub enum ConfigTarget {
+    Repo,
+    User,
+}
+
+pub enum SecretSource {
+    Prompt,
+    Generate,
+    Stdin,
+}
+
+impl Error {
+    /// Returns the path the error is about, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            DecryptionFailed(path, _)
+            | EncryptionFailed(path, _)
+            | FieldDoesNotExist(path, _)
+            | FileDoesNotExist(path)
+            | InvalidConfig(path, _)
+            | IoFailed(path, _) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Returns a stable, machine-readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            AgentAlreadyRunning => "agent_already_running",
+            AgentFailed(_) => "agent_failed",
+            AlreadyInitialized => "already_initialized",
+            BackendFailed(_) => "backend_failed",
+            CannotCopyMany => "cannot_copy_many",
+            CannotInitRepo => "cannot_init_repo",
+            ClipboardFailed(_) => "clipboard_failed",
+            FileDoesNotExist(_) => "file_does_not_exist",
+            GitFailed(_) => "git_failed",
+            HookFailed(_) => "hook_failed",
+            InputFailed(_) => "input_failed",
+            InputsDidntMatch => "inputs_did_not_match",
+            NoRepo => "no_repo",
+            TargetExists(_) => "target_exists",
+            TargetIsDirectory(_) => "target_is_directory",
+            CannotCreateDirectory(_) => "cannot_create_directory",
+            DecryptionFailed(_, _) => "decryption_failed",
+            EditorFailed(_) => "editor_failed",
+            EncryptionFailed(_, _) => "encryption_failed",
+            FieldDoesNotExist(_, _) => "field_does_not_exist",
+            InvalidConfig(_, _) => "invalid_config",
+            InvalidFieldName(_) => "invalid_field_name",
+            InvalidPath(_) => "invalid_path",
+            InvalidPattern(_) => "invalid_pattern",
+            IoFailed(_, _) => "io_failed",
+            NoAgent => "no_agent",
+            NoClipboard => "no_clipboard",
+            NoRekeyInProgress => "no_rekey_in_progress",
+            PasswordGenerationFailed(_) => "password_generation_failed",
+            RekeyCannotAbort => "rekey_cannot_abort",
+            RekeyInProgress => "rekey_in_progress",
+            UnknownSetting(_) => "unknown_setting",
+            UnlockFailed => "unlock_failed",
+            Untrusted(_) => "untrusted",
+            Usage => "usage",
+        }
+    }
+
+    /// Returns the exit status of the process for the error:
+    ///
+    /// - 1: Other errors
+    /// - 2: Invalid command line arguments
+    /// - 3: No repository
+    /// - 4: The secret or field does not exist
+    /// - 5: Wrong master passphrase
+    /// - 6: Encrypting or decrypting failed, or the backend could not run
+    /// - 7: Reading or writing files failed
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Usage | CannotCopyMany | InvalidFieldName(_) | InvalidPath(_) | InvalidPattern(_)
+            | UnknownSetting(_) => 2,
+            NoRepo => 3,
+            FileDoesNotExist(_) | FieldDoesNotExist(_, _) => 4,
+            UnlockFailed => 5,
+            DecryptionFailed(_, _) | EncryptionFailed(_, _) | BackendFailed(_) => 6,
+            IoFailed(_, _) | InputFailed(_) | CannotCreateDirectory(_) | CannotInitRepo => 7,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AgentAlreadyRunning => write!(f, "The agent is already running"),
+            AgentFailed(message) => write!(f, "Agent failed: {}", message),
+            AlreadyInitialized => write!(f, "The master key already exists"),
+            BackendFailed(message) => write!(f, "The encryption backend failed: {}", message),
+            CannotCopyMany => write!(f, "Only a single secret can be copied to the clipboard"),
+            CannotInitRepo => write!(f, "Failed to initialize a new repository"),
+            ClipboardFailed(message) => write!(f, "Copying to the clipboard failed: {}", message),
+            FileDoesNotExist(path) => write!(
+                f,
+                "File does not exist or invalid: {}",
+                path.to_string_lossy()
+            ),
+            GitFailed(message) => write!(f, "Git failed: {}", message),
+            HookFailed(message) => {
+                // The hook's stderr follows the first line
+                let (first, rest) = message.split_once('\n').unwrap_or((message, ""));
+                write!(f, "{}, aborting", first)?;
+                if !rest.is_empty() {
+                    write!(f, "\n{}", rest)?;
+                }
+                Ok(())
+            }
+            InputFailed(message) => write!(f, "Reading input failed: {}", message),
+            InputsDidntMatch => write!(f, "Inputs did not match."),
+            NoRepo => write!(f, "No repository. Run `sala init' first"),
+            TargetExists(path) => write!(
+                f,
+                "Target already exists: {}\nUse --force to replace it",
+                path.to_string_lossy()
+            ),
+            TargetIsDirectory(path) => {
+                write!(f, "Target is a directory: {}", path.to_string_lossy())
+            }
+            CannotCreateDirectory(path) => {
+                write!(f, "Cannot create directory: {}", path.to_string_lossy())
+            }
+            DecryptionFailed(path, message) => write!(
+                f,
+                "Unable to decrypt {}: {}",
+                path.to_string_lossy(),
+                message
+            ),
+            EditorFailed(message) => write!(f, "Editing failed: {}", message),
+            EncryptionFailed(path, message) => write!(
+                f,
+                "Unable to encrypt {}: {}",
+                path.to_string_lossy(),
+                message
+            ),
+            FieldDoesNotExist(path, name) => {
+                write!(f, "No field {} in {}", name, path.to_string_lossy())
+            }
+            InvalidConfig(path, message) => {
+                write!(
+                    f,
+                    "Invalid config file {}: {}",
+                    path.to_string_lossy(),
+                    message
+                )
+            }
+            InvalidFieldName(name) => write!(f, "Invalid field name: {}", name),
+            InvalidPath(path) => write!(f, "Not a valid secret path: {}", path.to_string_lossy()),
+            InvalidPattern(message) => write!(f, "Invalid pattern: {}", message),
+            IoFailed(path, err) => write!(f, "{}: {}", path.to_string_lossy(), err),
+            NoAgent => write!(f, "The agent is not running"),
+            NoClipboard => write!(f, "No clipboard command configured, set clipboard-copy"),
+            NoRekeyInProgress => write!(f, "No master key rotation in progress"),
+            PasswordGenerationFailed(message) => {
+                write!(f, "Generating a password failed: {}", message)
+            }
+            RekeyCannotAbort => write!(
+                f,
+                "The new master key is already in use, the rotation can't be aborted\n\
+                 Run `sala rekey --continue' to finish it"
+            ),
+            RekeyInProgress => write!(
+                f,
+                "A master key rotation is in progress\n\
+                 Run `sala rekey --continue' or `sala rekey --abort' first"
+            ),
+            UnknownSetting(key) => write!(f, "Unknown setting: {}", key),
+            UnlockFailed => write!(f, "Unable to unlock the encryption key"),
+            Untrusted(what) => write!(f, "{}, run `sala trust' to review it", what),
+            Usage => write!(f, "Try `sala --help'"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IoFailed(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> Error {
+    let path = path.to_path_buf();
+    move |err| IoFailed(path, err)
+}
+
+// The crypto backends fail either because of the files, or with the
+// error message of gpg
+fn decryption_error(path: &Path) -> impl FnOnce(GpgError) -> Error {
+    let path = path.to_path_buf();
+    move |err| match err {
+        GpgError::IOError(err) => IoFailed(path, err),
+        GpgError::OperationFailed(message) | GpgError::Unsupported(message) => {
+            DecryptionFailed(path, message)
+        }
+        GpgError::BackendFailed(message) => BackendFailed(message),
+    }
+}
+
+fn encryption_error(path: &Path) -> impl FnOnce(GpgError) -> Error {
+    let path = path.to_path_buf();
+    move |err| match err {
+        GpgError::IOError(err) => IoFailed(path, err),
+        GpgError::OperationFailed(message) | GpgError::Unsupported(message) => {
+            EncryptionFailed(path, message)
+        }
+        GpgError::BackendFailed(message) => BackendFailed(message),
+    }
+}
+
+fn unlock_error(path: &Path) -> impl FnOnce(GpgError) -> Error {
+    let path = path.to_path_buf();
+    move |err| match err {
+        GpgError::IOError(err) => IoFailed(path, err),
+        GpgError::OperationFailed(_) | GpgError::Unsupported(_) => UnlockFailed,
+        GpgError::BackendFailed(message) => BackendFailed(message),
+    }
+}
+
 use Error::*;
 use Output::*;
 
+const KEY_FILE: &str = ".sala/key";
+
 const INIT_MESSAGE: &str = "\
 Please pick a master passphrase. It is used to encrypt a very long
 random key, which in turn is used to encrypt all the private data in
@@ -43,23 +401,81 @@ Make sure you remember the master passphrase and that it's strong
 enough for your privacy needs.
 ";
 
-fn read_password(prompt: &str) -> String {
+fn read_password(prompt: &str) -> Result<String, Error> {
     let result = if atty::is(atty::Stream::Stdin) {
         rpassword::read_password_from_tty(Some(prompt))
     } else {
         rpassword::prompt_password_stderr(prompt)
     };
-    match result {
-        Ok(password) => password,
+    result.map_err(|e| InputFailed(e.to_string()))
+}
+
+fn read_line(prompt: &str) -> Result<String, Error> {
+    eprint!("{}", prompt);
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| InputFailed(e.to_string()))?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
 
-        // TODO: Error reading password, handle it somehow?
-        Err(_) => String::from(""),
+fn strip_newline(mut input: String) -> String {
+    if input.ends_with('\n') {
+        input.pop();
+        if input.ends_with('\r') {
+            input.pop();
+        }
+    }
+    input
+}
+
+// Reads the master passphrase from --passphrase-fd or SALA_PASSPHRASE_FILE
+// if either is given, and asks for it otherwise
+fn read_master_passphrase(config: &Config, prompt: &str) -> Result<String, Error> {
+    let result = if let Some(fd) = config.passphrase_fd {
+        // The descriptor belongs to the caller, don't close it
+        let mut file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+        let mut passphrase = String::new();
+        file.read_to_string(&mut passphrase).map(|_| passphrase)
+    } else if let Some(path) = &config.passphrase_file {
+        fs::read_to_string(path)
+    } else {
+        return read_password(prompt);
+    };
+    result
+        .map(strip_newline)
+        .map_err(|e| InputFailed(e.to_string()))
+}
+
+fn has_passphrase_source(config: &Config) -> bool {
+    config.passphrase_fd.is_some() || config.passphrase_file.is_some()
+}
+
+fn read_fields() -> Result<Vec<(String, String)>, Error> {
+    let mut fields = Vec::new();
+    loop {
+        let name = read_line("Field name (empty to finish): ")?;
+        if name.is_empty() {
+            return Ok(fields);
+        }
+        if !fields::is_valid_name(&name) {
+            return Err(InvalidFieldName(name));
+        }
+
+        // A trailing backslash continues the value on the next line
+        let mut value = read_line(&format!("Value for {}: ", name))?;
+        while value.ends_with('\\') {
+            value.pop();
+            value.push('\n');
+            value.push_str(&read_line("> ")?);
+        }
+        fields.push((name, value));
     }
 }
 
 fn read_secret(prompt1: &str, prompt2: &str) -> Result<String, Error> {
-    let input1 = read_password(prompt1);
-    let input2 = read_password(prompt2);
+    let input1 = read_password(prompt1)?;
+    let input2 = read_password(prompt2)?;
     if input1 == input2 {
         Ok(input1)
     } else {
@@ -68,21 +484,22 @@ fn read_secret(prompt1: &str, prompt2: &str) -> Result<String, Error> {
 }
 
 fn read_secret_or_choice(
+    config: &Config,
     prompt1: &str,
     prompt2: &str,
-    choices: &Vec<String>,
+    choices: &[String],
 ) -> Result<String, Error> {
-    println!("");
+    message!(config, "\n");
     for (i, choice) in choices.iter().enumerate() {
-        println!("{}. {}", i, choice);
+        message!(config, "{}. {}\n", i, choice);
     }
-    println!("");
+    message!(config, "\n");
 
-    let input1 = read_password(prompt1);
+    let input1 = read_password(prompt1)?;
     match input1.parse::<usize>() {
         Ok(index) if index < choices.len() => Ok(choices.get(index).unwrap().to_owned()),
         _ => {
-            let input2 = read_password(prompt2);
+            let input2 = read_password(prompt2)?;
             if input1 == input2 {
                 Ok(input1)
             } else {
@@ -92,42 +509,184 @@ fn read_secret_or_choice(
     }
 }
 
-fn unlock_repo(repo_path: &Path) -> Result<Vec<u8>, Error> {
-    let key_path = repo_path.join(".sala/key");
+fn read_master_key(
+    repo_path: &Path,
+    config: &Config,
+    crypto: &dyn Crypto,
+) -> Result<Vec<u8>, Error> {
+    let key_path = repo_path.join(KEY_FILE);
     if !key_path.is_file() {
         Err(NoRepo)
+    } else if repo_path.join(REKEY_DIR).exists() {
+        Err(RekeyInProgress)
     } else {
-        let passphrase = read_password("Enter the master passphrase: ");
-        gpg::decrypt(&key_path, &passphrase.as_bytes()).map_err(|_| UnlockFailed)
-    }
-}
-
-fn generate_suggestions(config: &Config) -> Option<Vec<String>> {
-    if let Some(parsed_cmd) = config
-        .password_generator
-        .to_owned()
-        .and_then(|cmd| shell_words::split(&cmd).ok())
-    {
-        Command::new(&parsed_cmd[0])
-            .args(&parsed_cmd[1..])
-            .output()
-            .ok()
-            .and_then(|output| String::from_utf8(output.stdout).ok())
-            .and_then(|s| {
-                let result: Vec<String> = s.split_whitespace().map(|t| t.into()).collect();
-                if result.len() == 0 {
-                    None
-                } else {
-                    Some(result)
-                }
-            })
+        let passphrase = read_master_passphrase(config, "Enter the master passphrase: ")?;
+        gpg::decrypt(crypto, &key_path, passphrase.as_bytes())
+            .map_err(unlock_error(Path::new(KEY_FILE)))
+    }
+}
+
+// Returns the master key and the warnings of the pre-unlock hooks
+fn unlock_repo(
+    repo_path: &Path,
+    config: &Config,
+    crypto: &dyn Crypto,
+) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let warnings =
+        run_pre_hook(repo_path, Path::new(""), Hook::PreUnlock, config).map_err(HookFailed)?;
+    if repo_path.join(KEY_FILE).is_file() && !repo_path.join(REKEY_DIR).exists() {
+        if let Some(master_key) = agent::get_key(repo_path) {
+            return Ok((master_key, warnings));
+        }
+    }
+    let master_key = read_master_key(repo_path, config, crypto)?;
+    agent::add_key(
+        repo_path,
+        &master_key,
+        config.agent_idle_timeout,
+        config.agent_max_lifetime,
+    );
+    Ok((master_key, warnings))
+}
+
+fn run_password_generator(config: &Config) -> Result<Vec<String>, Error> {
+    let cmd = config.password_generator.as_deref().unwrap_or_default();
+    let output = command::parse(cmd)
+        .and_then(|mut command| command.output())
+        .map_err(|e| PasswordGenerationFailed(e.to_string()))?;
+    let words: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(|t| t.into())
+        .collect();
+    if words.is_empty() {
+        Err(PasswordGenerationFailed(
+            "password-generator produced no output".to_string(),
+        ))
     } else {
-        None
+        Ok(words)
+    }
+}
+
+// The name of a setting in a rule, as it's trusted and reported
+fn rule_setting_name(pattern: &str, setting: &str) -> String {
+    format!("rules.{:?}.{}", pattern, setting)
+}
+
+// Returns the commands that come from the store's config, by setting
+// name. A password generator in a rule is trusted separately from the
+// others.
+fn repo_commands(config: &Config) -> Vec<(&'static str, String, &str)> {
+    let generator_name = match &config.password_generator_rule {
+        Some(pattern) => rule_setting_name(pattern, "password-generator"),
+        None => "password-generator".to_string(),
+    };
+    let commands = vec![
+        (
+            "password-generator",
+            generator_name,
+            &config.password_generator,
+            config.password_generator_from_repo,
+        ),
+        (
+            "clipboard-copy",
+            "clipboard-copy".to_string(),
+            &config.clipboard_copy,
+            config.clipboard_copy_from_repo,
+        ),
+        (
+            "clipboard-paste",
+            "clipboard-paste".to_string(),
+            &config.clipboard_paste,
+            config.clipboard_paste_from_repo,
+        ),
+    ];
+    commands
+        .into_iter()
+        .filter(|(_, _, _, from_repo)| *from_repo)
+        .filter_map(|(setting, name, command, _)| {
+            command.as_deref().map(|command| (setting, name, command))
+        })
+        .collect()
+}
+
+// Commands from the store's config run only if the user has trusted them
+fn check_command_trust(repo_path: &Path, config: &Config, settings: &[&str]) -> Result<(), Error> {
+    for (setting, name, command) in repo_commands(config) {
+        if !settings.contains(&setting) {
+            continue;
+        }
+        let key = trust::command_key(repo_path, &name);
+        let reason = match trust::check(&key, command.as_bytes()) {
+            Trust::Trusted => continue,
+            Trust::Untrusted => "is not trusted",
+            Trust::Changed => "has changed since it was trusted",
+        };
+        return Err(Untrusted(format!("{} in .sala/config {}", name, reason)));
+    }
+    Ok(())
+}
+
+fn generate_password(config: &Config) -> Result<String, Error> {
+    generator::generate(config).map_err(|e| PasswordGenerationFailed(e.to_string()))
+}
+
+fn generate_suggestions(config: &Config) -> Result<Option<Vec<String>>, Error> {
+    if config.password_generator.is_some() {
+        // A failing generator just means no suggestions
+        Ok(run_password_generator(config).ok())
+    } else if config.password_suggestions > 0 {
+        (0..config.password_suggestions)
+            .map(|_| generate_password(config))
+            .collect::<Result<Vec<String>, Error>>()
+            .map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn generate_key(config: &Config) -> io::Result<String> {
+    let mut rng = OsRng::new()?;
+    let mut key: Vec<u8> = vec![0; config.key_length];
+    rng.fill_bytes(&mut key);
+    Ok(key
+        .iter()
+        .map(|&b| format!("{:x}", b))
+        .collect::<Vec<String>>()
+        .concat())
+}
+
+pub fn start_agent(config: &Config, foreground: bool) -> Result<Output, Error> {
+    if agent::is_running() {
+        return Err(AgentAlreadyRunning);
+    }
+    let socket_path = agent::socket_path();
+    let listener = agent::listen(&socket_path).map_err(|e| AgentFailed(e.to_string()))?;
+    message!(
+        config,
+        "Agent listening on {}\n",
+        socket_path.to_string_lossy()
+    );
+
+    if !foreground {
+        unistd::daemon(false, false).map_err(|e| AgentFailed(e.to_string()))?;
     }
+    // Keep other processes from reading the keys through ptrace or core
+    // dumps
+    #[cfg(target_os = "linux")]
+    unsafe {
+        nix::libc::prctl(nix::libc::PR_SET_DUMPABLE, 0);
+    }
+    agent::serve(listener).map_err(|e| AgentFailed(e.to_string()))?;
+    Ok(NoOutput)
+}
+
+pub fn lock() -> Result<Output, Error> {
+    agent::lock().map_err(|_| NoAgent)?;
+    Ok(NoOutput)
 }
 
-pub fn init(repo_path: &Path, config: &Config) -> Result<Output, Error> {
-    let key_path = repo_path.join(".sala/key");
+pub fn init(repo_path: &Path, config: &Config, with_git: bool) -> Result<Output, Error> {
+    let key_path = repo_path.join(KEY_FILE);
     if key_path.exists() {
         return Err(AlreadyInitialized);
     }
@@ -138,66 +697,598 @@ pub fn init(repo_path: &Path, config: &Config) -> Result<Output, Error> {
     } else {
         return Err(AlreadyInitialized);
     }
-    println!("{}", INIT_MESSAGE);
+    message!(config, "{}\n", INIT_MESSAGE);
 
-    let master_passphrase = read_secret("Enter a master passphrase: ", "Confirm: ")?;
+    let master_passphrase = if has_passphrase_source(config) {
+        read_master_passphrase(config, "")?
+    } else {
+        read_secret("Enter a master passphrase: ", "Confirm: ")?
+    };
 
-    println!("");
-    print!(
-        "Generating a master key ({} bits)...",
+    message!(
+        config,
+        "\nGenerating a master key ({} bits)...",
         config.key_length * 8
     );
-    let mut rng = OsRng::new().unwrap();
-    let mut key: Vec<u8> = Vec::new();
-    key.resize(config.key_length, 0);
-    rng.fill_bytes(&mut key);
-    let key_ascii: String = key
-        .iter()
-        .map(|&b| format!("{:x}", b))
-        .collect::<Vec<String>>()
-        .concat();
-    println!(" done");
+    let key_ascii = generate_key(config).map_err(io_error(Path::new(KEY_FILE)))?;
+    message!(config, " done\n");
 
+    let crypto = gpg::crypto(&config.backend);
     gpg::encrypt(
-        &key_ascii,
-        &master_passphrase.as_bytes(),
+        &*crypto,
+        key_ascii.as_bytes(),
+        master_passphrase.as_bytes(),
         &key_path,
         &config.cipher,
     )
-    .unwrap();
-    Ok(NoOutput)
+    .map_err(encryption_error(Path::new(KEY_FILE)))?;
+
+    let mut warnings = Vec::new();
+    if with_git {
+        // Committed even without git-auto-commit, so that the new store
+        // starts out clean
+        git::init(repo_path).map_err(GitFailed)?;
+        warnings.extend(git::commit(
+            repo_path,
+            &[Path::new(".sala"), Path::new(".gitattributes")],
+            "Initialize the password store",
+        ));
+    }
+    warnings
+        .extend(run_hook(repo_path, Path::new(""), Hook::PostInit, config).map_err(HookFailed)?);
+    Ok(Put(warnings))
 }
 
-pub fn get(repo_path: &Path, path: &Path, raw: bool) -> Result<Output, Error> {
+pub fn passwd(repo_path: &Path, config: &Config) -> Result<Output, Error> {
+    let crypto = gpg::crypto(&config.backend);
+    let mut warnings =
+        run_pre_hook(repo_path, Path::new(""), Hook::PreUnlock, config).map_err(HookFailed)?;
+    let master_key = read_master_key(repo_path, config, &*crypto)?;
+    let new_passphrase = read_secret("Enter a new master passphrase: ", "Confirm: ")?;
+
+    gpg::encrypt(
+        &*crypto,
+        &master_key,
+        new_passphrase.as_bytes(),
+        &repo_path.join(KEY_FILE),
+        &config.cipher,
+    )
+    .map_err(encryption_error(Path::new(KEY_FILE)))?;
+
+    warnings.extend(auto_commit(
+        repo_path,
+        config,
+        &[Path::new(KEY_FILE)],
+        "Change the master passphrase",
+    ));
+    Ok(Put(warnings))
+}
+
+// Master key rotation keeps its state in REKEY_DIR: the new master key
+// (encrypted with the master passphrase) and a journal of the secrets
+// that have already been re-encrypted with it. .sala/key is replaced
+// only after all secrets have been re-encrypted.
+const REKEY_DIR: &str = ".sala/rekey";
+const TRASH_DIR: &str = ".sala/trash";
+
+fn read_rekey_journal(journal_path: &Path) -> HashSet<PathBuf> {
+    match fs::File::open(journal_path) {
+        Ok(file) => BufReader::new(file)
+            .split(b'\n')
+            .filter_map(|line| line.ok())
+            .map(|line| PathBuf::from(OsStr::from_bytes(&line)))
+            .collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+// Re-encrypts a secret from one master key to another. A secret that
+// already decrypts with the target key is left alone, which makes it safe
+// to retry after an interruption.
+fn rekey_secret(
+    crypto: &dyn Crypto,
+    repo_path: &Path,
+    path: &Path,
+    from_key: &[u8],
+    to_key: &[u8],
+    cipher: &str,
+) -> Result<(), Error> {
+    let full_path = repo_path.join(path);
+    match gpg::decrypt(crypto, &full_path, from_key) {
+        Ok(secret) => gpg::encrypt(crypto, &secret, to_key, &full_path, cipher)
+            .map_err(encryption_error(path)),
+        Err(_) => gpg::decrypt(crypto, &full_path, to_key)
+            .map(|_| ())
+            .map_err(decryption_error(path)),
+    }
+}
+
+pub fn rekey(repo_path: &Path, config: &Config, mode: RekeyMode) -> Result<Output, Error> {
+    let key_path = repo_path.join(KEY_FILE);
+    let rekey_path = repo_path.join(REKEY_DIR);
+    let new_key_path = rekey_path.join("key");
+    let journal_path = rekey_path.join("journal");
+
+    if !key_path.is_file() {
+        return Err(NoRepo);
+    }
+    match (&mode, rekey_path.exists()) {
+        (RekeyMode::Start, true) => return Err(RekeyInProgress),
+        (RekeyMode::Continue, false) | (RekeyMode::Abort, false) => return Err(NoRekeyInProgress),
+        // .sala/key has already been replaced, and the old key is gone
+        (RekeyMode::Abort, true) if !new_key_path.exists() => return Err(RekeyCannotAbort),
+        _ => {}
+    }
+
+    let crypto = gpg::crypto(&config.backend);
+    let mut warnings =
+        run_pre_hook(repo_path, Path::new(""), Hook::PreUnlock, config).map_err(HookFailed)?;
+    let passphrase = read_master_passphrase(config, "Enter the master passphrase: ")?;
+    let old_key = gpg::decrypt(&*crypto, &key_path, passphrase.as_bytes())
+        .map_err(unlock_error(Path::new(KEY_FILE)))?;
+
+    let new_key = match mode {
+        RekeyMode::Start => {
+            message!(
+                config,
+                "\nGenerating a new master key ({} bits)...",
+                config.key_length * 8
+            );
+            let new_key = generate_key(config)
+                .map_err(io_error(Path::new(REKEY_DIR)))?
+                .into_bytes();
+
+            // Write the new key first and only then make the rekey visible,
+            // so that a rekey in progress always has its key
+            let tmp_path = repo_path.join(format!("{}.tmp", REKEY_DIR));
+            fs::create_dir_all(&tmp_path)
+                .map_err(|_| CannotCreateDirectory(PathBuf::from(REKEY_DIR)))?;
+            gpg::encrypt(
+                &*crypto,
+                &new_key,
+                passphrase.as_bytes(),
+                &tmp_path.join("key"),
+                &config.cipher,
+            )
+            .map_err(encryption_error(Path::new(REKEY_DIR)))?;
+            fs::rename(&tmp_path, &rekey_path)
+                .map_err(|_| CannotCreateDirectory(PathBuf::from(REKEY_DIR)))?;
+            message!(config, " done\n");
+            new_key
+        }
+        _ if !new_key_path.exists() => {
+            // Interrupted right after replacing .sala/key, only the
+            // cleanup is left
+            fs::remove_dir_all(&rekey_path).map_err(io_error(Path::new(REKEY_DIR)))?;
+            return Ok(Put(warnings));
+        }
+        _ => gpg::decrypt(&*crypto, &new_key_path, passphrase.as_bytes())
+            .map_err(unlock_error(Path::new(REKEY_DIR)))?,
+    };
+
+    // Trashed secrets must stay recoverable with the new key, too
+    let mut secrets = walk::secrets(repo_path, Path::new("")).map_err(io_error(repo_path))?;
+    if repo_path.join(TRASH_DIR).is_dir() {
+        secrets.extend(
+            walk::secrets(repo_path, Path::new(TRASH_DIR))
+                .map_err(io_error(Path::new(TRASH_DIR)))?,
+        );
+    }
+    if let RekeyMode::Abort = mode {
+        message!(config, "Restoring {} secrets...", secrets.len());
+        for path in secrets.iter() {
+            rekey_secret(
+                &*crypto,
+                repo_path,
+                path,
+                &new_key,
+                &old_key,
+                &config.for_path(path).cipher,
+            )?;
+        }
+        fs::remove_dir_all(&rekey_path).map_err(io_error(Path::new(REKEY_DIR)))?;
+        message!(config, " done\n");
+
+        let paths: Vec<&Path> = secrets.iter().map(PathBuf::as_path).collect();
+        warnings.extend(auto_commit(
+            repo_path,
+            config,
+            &paths,
+            "Re-encrypt all secrets after an aborted master key rotation",
+        ));
+        return Ok(Put(warnings));
+    }
+
+    message!(config, "Re-encrypting {} secrets...", secrets.len());
+    let done = read_rekey_journal(&journal_path);
+    let mut journal = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal_path)
+        .map_err(io_error(&Path::new(REKEY_DIR).join("journal")))?;
+    for path in secrets.iter().filter(|path| !done.contains(*path)) {
+        rekey_secret(
+            &*crypto,
+            repo_path,
+            path,
+            &old_key,
+            &new_key,
+            &config.for_path(path).cipher,
+        )?;
+        journal
+            .write_all(path.as_os_str().as_bytes())
+            .and_then(|_| journal.write_all(b"\n"))
+            .and_then(|_| journal.sync_data())
+            .map_err(io_error(&Path::new(REKEY_DIR).join("journal")))?;
+    }
+
+    fs::rename(&new_key_path, &key_path)
+        .and_then(|_| gpg::sync_parent(&key_path))
+        .map_err(io_error(Path::new(KEY_FILE)))?;
+    fs::remove_dir_all(&rekey_path).map_err(io_error(Path::new(REKEY_DIR)))?;
+    message!(config, " done\n");
+
+    let mut paths: Vec<&Path> = secrets.iter().map(PathBuf::as_path).collect();
+    paths.push(Path::new(KEY_FILE));
+    warnings.extend(auto_commit(
+        repo_path,
+        config,
+        &paths,
+        "Re-encrypt all secrets with a new master key",
+    ));
+    Ok(Put(warnings))
+}
+
+pub fn list(repo_path: &Path, dir: &Path, raw: bool) -> Result<Output, Error> {
+    if !repo_path.join(KEY_FILE).is_file() {
+        return Err(NoRepo);
+    }
+    if !repo_path.join(dir).is_dir() || !walk::is_visible(dir) {
+        return Err(FileDoesNotExist(dir.to_path_buf()));
+    }
+    let secrets = walk::secrets(repo_path, dir).map_err(|_| FileDoesNotExist(dir.to_path_buf()))?;
+    Ok(List(dir.to_path_buf(), secrets, raw))
+}
+
+pub fn find(
+    repo_path: &Path,
+    pattern: &str,
+    regex: bool,
+    ignore_case: bool,
+    raw: bool,
+) -> Result<Output, Error> {
+    if !repo_path.join(KEY_FILE).is_file() {
+        return Err(NoRepo);
+    }
+
+    let matcher: Box<dyn Fn(&str) -> bool> = if regex {
+        let re = RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| InvalidPattern(e.to_string()))?;
+        Box::new(move |path| re.is_match(path))
+    } else {
+        let glob = glob::Pattern::new(pattern).map_err(|e| InvalidPattern(e.to_string()))?;
+        let options = glob::MatchOptions {
+            case_sensitive: !ignore_case,
+            require_literal_separator: false,
+            require_literal_leading_dot: false,
+        };
+        Box::new(move |path| glob.matches_with(path, options))
+    };
+
+    let secrets = walk::secrets(repo_path, Path::new(""))
+        .map_err(|_| FileDoesNotExist(repo_path.to_path_buf()))?
+        .into_iter()
+        .filter(|path| matcher(&path.to_string_lossy()))
+        .collect();
+    Ok(List(PathBuf::new(), secrets, raw))
+}
+
+pub fn grep(
+    repo_path: &Path,
+    config: &Config,
+    pattern: &str,
+    ignore_case: bool,
+    show_lines: bool,
+) -> Result<Output, Error> {
+    let re = RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| InvalidPattern(e.to_string()))?;
+
+    // Every secret is read, so the post-get hooks run for all of them.
+    // The pre-get hooks run once for the whole store.
+    let secrets = walk::secrets(repo_path, Path::new(""))
+        .map_err(|_| FileDoesNotExist(repo_path.to_path_buf()))?;
+    let mut hook_warnings =
+        run_pre_hook(repo_path, Path::new(""), Hook::PreGet, config).map_err(HookFailed)?;
+    let crypto = gpg::crypto(&config.backend);
+    let (master_key, warnings) = unlock_repo(repo_path, config, &*crypto)?;
+    hook_warnings.extend(warnings);
+
+    let mut matches = Vec::new();
+    for path in secrets {
+        let (secret, warnings) = read_secret_file(
+            &*crypto,
+            repo_path,
+            &config.for_path(&path),
+            &path,
+            &master_key,
+            None,
+        )?;
+        hook_warnings.extend(warnings);
+        let lines: Vec<String> = String::from_utf8_lossy(&secret)
+            .lines()
+            .filter(|line| re.is_match(line))
+            .map(String::from)
+            .collect();
+        if !lines.is_empty() {
+            matches.push((path, lines));
+        }
+    }
+    Ok(Matches(matches, show_lines, hook_warnings))
+}
+
+// Decrypts a secret, runs the post-get hooks and picks the field if one
+// was requested. Returns the result and the hook warnings.
+fn read_secret_file(
+    crypto: &dyn Crypto,
+    repo_path: &Path,
+    config: &Config,
+    path: &Path,
+    master_key: &[u8],
+    field: Option<&str>,
+) -> Result<(Vec<u8>, Vec<String>), Error> {
+    let secret =
+        gpg::decrypt(crypto, &repo_path.join(path), master_key).map_err(decryption_error(path))?;
+
+    let hook_warnings =
+        run_hook(repo_path, path, Hook::PostGet(secret.clone()), config).map_err(HookFailed)?;
+    let secret = match field {
+        Some(name) => fields::get(&String::from_utf8_lossy(&secret), name)
+            .ok_or_else(|| FieldDoesNotExist(path.to_path_buf(), name.to_string()))?
+            .into_bytes(),
+        None => secret,
+    };
+    Ok((secret, hook_warnings))
+}
+
+fn get_one(
+    repo_path: &Path,
+    path: &Path,
+    config: &Config,
+    raw: bool,
+    clip: bool,
+    field: Option<&str>,
+) -> Result<Output, Error> {
     let full_path = repo_path.join(path);
 
     if !full_path.is_file() {
         return Err(FileDoesNotExist(path.to_path_buf()));
     }
-    let master_key = unlock_repo(repo_path)?;
-    let secret = gpg::decrypt(&full_path, &master_key).unwrap();
+    if clip && config.clipboard_copy.is_none() {
+        return Err(NoClipboard);
+    }
+    if clip {
+        check_command_trust(repo_path, config, &["clipboard-copy", "clipboard-paste"])?;
+    }
+    let config = &config.for_path(path);
+    let mut hook_warnings =
+        run_pre_hook(repo_path, path, Hook::PreGet, config).map_err(HookFailed)?;
+    let crypto = gpg::crypto(&config.backend);
+    let (master_key, warnings) = unlock_repo(repo_path, config, &*crypto)?;
+    hook_warnings.extend(warnings);
+    let (secret, warnings) =
+        read_secret_file(&*crypto, repo_path, config, path, &master_key, field)?;
+    hook_warnings.extend(warnings);
+
+    if let (true, Some(copy_cmd)) = (clip, &config.clipboard_copy) {
+        clipboard::copy_secret(
+            copy_cmd,
+            config.clipboard_paste.as_deref(),
+            &secret,
+            config.clipboard_clear_after,
+        )
+        .map_err(|e| ClipboardFailed(e.to_string()))?;
+        return Ok(Copied(
+            path.to_path_buf(),
+            config.clipboard_clear_after,
+            raw,
+            hook_warnings,
+        ));
+    }
+    Ok(Get(
+        path.to_path_buf(),
+        field.map(|name| name.to_string()),
+        secret,
+        raw,
+        hook_warnings,
+    ))
+}
+
+pub fn get(
+    repo_path: &Path,
+    paths: &[&Path],
+    config: &Config,
+    raw: bool,
+    clip: bool,
+    field: Option<&str>,
+) -> Result<Output, Error> {
+    if let [path] = paths {
+        if !repo_path.join(path).is_dir() {
+            return get_one(repo_path, path, config, raw, clip, field);
+        }
+    }
+    if clip {
+        return Err(CannotCopyMany);
+    }
+
+    // Directories are expanded to the secrets in them. Paths that don't
+    // exist are reported one by one, without aborting the rest.
+    let mut targets: Vec<Result<PathBuf, Error>> = Vec::new();
+    for &path in paths {
+        let full_path = repo_path.join(path);
+        if full_path.is_dir() && walk::is_visible(path) {
+            match walk::secrets(repo_path, path) {
+                Ok(secrets) => targets.extend(secrets.into_iter().map(Ok)),
+                Err(_) => targets.push(Err(FileDoesNotExist(path.to_path_buf()))),
+            }
+        } else if full_path.is_file() {
+            targets.push(Ok(path.to_path_buf()));
+        } else {
+            targets.push(Err(FileDoesNotExist(path.to_path_buf())));
+        }
+    }
 
-    let hook_warnings = match str::from_utf8(&secret) {
-        Ok(s) => run_hook(repo_path, path, Hook::PostGet(s.to_string())),
-        Err(_) => vec!["Cannot run hooks: secret is not valid UTF-8".to_string()],
+    // The pre-get hooks run once, and only get the path if there's a
+    // single secret
+    let paths: Vec<&PathBuf> = targets.iter().flatten().collect();
+    let mut hook_warnings = match paths.as_slice() {
+        [] => Vec::new(),
+        [path] => run_pre_hook(repo_path, path, Hook::PreGet, &config.for_path(path))
+            .map_err(HookFailed)?,
+        _ => run_pre_hook(repo_path, Path::new(""), Hook::PreGet, config).map_err(HookFailed)?,
     };
-    Ok(Get(path.to_path_buf(), secret, raw, hook_warnings))
+
+    // No need for the passphrase if there's nothing to decrypt
+    let crypto = gpg::crypto(&config.backend);
+    let master_key = if targets.iter().any(|target| target.is_ok()) {
+        let (master_key, warnings) = unlock_repo(repo_path, config, &*crypto)?;
+        hook_warnings.extend(warnings);
+        master_key
+    } else {
+        Vec::new()
+    };
+
+    let mut results = Vec::new();
+    for target in targets {
+        results.push(target.and_then(|path| {
+            let (secret, warnings) = read_secret_file(
+                &*crypto,
+                repo_path,
+                &config.for_path(&path),
+                &path,
+                &master_key,
+                field,
+            )?;
+            hook_warnings.extend(warnings);
+            Ok((path, secret))
+        }));
+    }
+    Ok(GetMany(results, raw, hook_warnings))
 }
 
-pub fn set(repo_path: &Path, path: &Path, config: &Config) -> Result<Output, Error> {
-    let full_path = repo_path.join(path);
+fn check_repo(repo_path: &Path) -> Result<(), Error> {
+    if !repo_path.join(KEY_FILE).is_file() {
+        Err(NoRepo)
+    } else if repo_path.join(REKEY_DIR).exists() {
+        Err(RekeyInProgress)
+    } else {
+        Ok(())
+    }
+}
+
+// Secrets live in plain relative paths outside .sala and other hidden
+// directories
+fn check_secret_path(path: &Path) -> Result<(), Error> {
+    let is_plain = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if is_plain && path.file_name().is_some() && walk::is_visible(path) {
+        Ok(())
+    } else {
+        Err(InvalidPath(path.to_path_buf()))
+    }
+}
+
+// Commits the changes of a command that modified the store. The commit
+// message names the paths but never the secrets.
+fn auto_commit(repo_path: &Path, config: &Config, paths: &[&Path], message: &str) -> Vec<String> {
+    if config.git_auto_commit && git::is_work_tree(repo_path) {
+        git::commit(repo_path, paths, message).into_iter().collect()
+    } else {
+        Vec::new()
+    }
+}
+
+fn set_commit_message(full_path: &Path, path: &Path) -> String {
+    let verb = if full_path.exists() { "Update" } else { "Add" };
+    format!("{} {}", verb, path.to_string_lossy())
+}
+
+fn create_parent_dirs(repo_path: &Path, path: &Path) -> Result<(), Error> {
     if let Some(path_parent) = path.parent() {
-        fs::create_dir_all(full_path.parent().unwrap())
+        fs::create_dir_all(repo_path.join(path_parent))
             .map_err(|_| CannotCreateDirectory(path_parent.to_path_buf()))?
     }
+    Ok(())
+}
+
+fn remove_empty_parent_dirs(repo_path: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(repo_path.join(dir)).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn set(
+    repo_path: &Path,
+    path: &Path,
+    config: &Config,
+    source: SecretSource,
+    with_fields: bool,
+) -> Result<Output, Error> {
+    check_secret_path(path)?;
+    let config = &config.for_path(path);
+    let mut warnings = run_pre_hook(repo_path, path, Hook::PreSet, config).map_err(HookFailed)?;
+    if !matches!(source, SecretSource::Stdin) {
+        check_command_trust(repo_path, config, &["password-generator"])?;
+    }
+    let full_path = repo_path.join(path);
+    create_parent_dirs(repo_path, path)?;
 
     if full_path.is_dir() {
         return Err(TargetIsDirectory(path.to_path_buf()));
     }
-    let master_key = unlock_repo(repo_path)?;
+    let generated = match source {
+        SecretSource::Generate if config.password_generator.is_some() => {
+            let words = run_password_generator(config)?;
+            Some(words[0].to_owned())
+        }
+        SecretSource::Generate => Some(generate_password(config)?),
+        _ => None,
+    };
+    let crypto = gpg::crypto(&config.backend);
+    let (master_key, unlock_warnings) = unlock_repo(repo_path, config, &*crypto)?;
+    warnings.extend(unlock_warnings);
+    let commit_message = set_commit_message(&full_path, path);
 
-    let new_secret = if let Some(suggestions) = generate_suggestions(config) {
+    if let SecretSource::Stdin = source {
+        // Taken as is, so binary and multi-line secrets are fine
+        let mut new_secret = Vec::new();
+        io::stdin()
+            .read_to_end(&mut new_secret)
+            .map_err(|e| InputFailed(e.to_string()))?;
+        gpg::encrypt(
+            &*crypto,
+            &new_secret,
+            &master_key,
+            &full_path,
+            &config.cipher,
+        )
+        .map_err(encryption_error(path))?;
+        warnings.extend(auto_commit(repo_path, config, &[path], &commit_message));
+        warnings.extend(run_hook(repo_path, path, Hook::PostSet, config).map_err(HookFailed)?);
+        return Ok(Put(warnings));
+    }
+
+    let new_secret = if let Some(generated) = generated {
+        Ok(generated)
+    } else if let Some(suggestions) = generate_suggestions(config)? {
         read_secret_or_choice(
+            config,
             &format!(
                 "Select a number from the list or type a new secret for {}: ",
                 path.to_string_lossy()
@@ -211,10 +1302,459 @@ pub fn set(repo_path: &Path, path: &Path, config: &Config) -> Result<Output, Err
             "Confirm: ",
         )
     }?;
-    gpg::encrypt(&new_secret, &master_key, &full_path, &config.cipher).unwrap();
+    let new_secret = if with_fields {
+        fields::format(&new_secret, &read_fields()?)
+    } else {
+        new_secret
+    };
+    gpg::encrypt(
+        &*crypto,
+        new_secret.as_bytes(),
+        &master_key,
+        &full_path,
+        &config.cipher,
+    )
+    .map_err(encryption_error(path))?;
+
+    warnings.extend(auto_commit(repo_path, config, &[path], &commit_message));
+    warnings.extend(run_hook(repo_path, path, Hook::PostSet, config).map_err(HookFailed)?);
+    Ok(Put(warnings))
+}
+
+pub fn edit(repo_path: &Path, path: &Path, config: &Config, raw: bool) -> Result<Output, Error> {
+    check_secret_path(path)?;
+    let config = &config.for_path(path);
+    let full_path = repo_path.join(path);
+    if full_path.is_dir() {
+        return Err(TargetIsDirectory(path.to_path_buf()));
+    }
+    let file_name = match path.file_name() {
+        Some(name) => name,
+        None => return Err(TargetIsDirectory(path.to_path_buf())),
+    };
+    // An existing secret is read before it's changed
+    let exists = full_path.is_file();
+    let mut warnings = Vec::new();
+    if exists {
+        warnings.extend(run_pre_hook(repo_path, path, Hook::PreGet, config).map_err(HookFailed)?);
+    }
+    warnings.extend(run_pre_hook(repo_path, path, Hook::PreSet, config).map_err(HookFailed)?);
+    let crypto = gpg::crypto(&config.backend);
+    let (master_key, unlock_warnings) = unlock_repo(repo_path, config, &*crypto)?;
+    warnings.extend(unlock_warnings);
+
+    let secret = if exists {
+        let (secret, get_warnings) =
+            read_secret_file(&*crypto, repo_path, config, path, &master_key, None)?;
+        warnings.extend(get_warnings);
+        secret
+    } else {
+        Vec::new()
+    };
+
+    // Editors like to end files with a newline, which is not part of
+    // the secret
+    let strip_newline = !secret.ends_with(b"\n");
+    let mut contents = secret.clone();
+    if strip_newline && !contents.is_empty() {
+        contents.push(b'\n');
+    }
+    let mut edited = editor::edit(file_name, &contents).map_err(|e| EditorFailed(e.to_string()))?;
+    if strip_newline && edited.ends_with(b"\n") {
+        edited.pop();
+    }
+    if edited == secret {
+        return Ok(Unchanged(path.to_path_buf(), raw, warnings));
+    }
+
+    create_parent_dirs(repo_path, path)?;
+    let commit_message = set_commit_message(&full_path, path);
+    gpg::encrypt(&*crypto, &edited, &master_key, &full_path, &config.cipher)
+        .map_err(encryption_error(path))?;
+
+    warnings.extend(auto_commit(repo_path, config, &[path], &commit_message));
+    warnings.extend(run_hook(repo_path, path, Hook::PostSet, config).map_err(HookFailed)?);
+    Ok(Put(warnings))
+}
+
+// Like mv and cp, a target that is an existing directory means a path
+// inside it. So does a target ending in "/", or the root of the store,
+// which need not exist yet.
+fn resolve_target(repo_path: &Path, source: &Path, target: &Path) -> Result<PathBuf, Error> {
+    check_secret_path(source)?;
+    let is_root =
+        !target.as_os_str().is_empty() && target.components().all(|c| c == Component::CurDir);
+    if !is_root {
+        check_secret_path(target)?;
+    }
+    if !repo_path.join(source).exists() {
+        return Err(FileDoesNotExist(source.to_path_buf()));
+    }
+    let is_dir =
+        is_root || target.as_os_str().as_bytes().ends_with(b"/") || repo_path.join(target).is_dir();
+    let target = if is_dir {
+        let dir: PathBuf = target
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect();
+        dir.join(source.file_name().unwrap())
+    } else {
+        target.to_path_buf()
+    };
+    if target.starts_with(source) {
+        return Err(InvalidPath(target));
+    }
+    Ok(target)
+}
+
+fn check_overwrite(
+    repo_path: &Path,
+    source: &Path,
+    target: &Path,
+    force: bool,
+) -> Result<(), Error> {
+    let full_target = repo_path.join(target);
+    if full_target.is_dir() {
+        Err(TargetIsDirectory(target.to_path_buf()))
+    } else if full_target.exists() && (!force || repo_path.join(source).is_dir()) {
+        Err(TargetExists(target.to_path_buf()))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn rename(
+    repo_path: &Path,
+    config: &Config,
+    source: &Path,
+    target: &Path,
+    force: bool,
+) -> Result<Output, Error> {
+    check_repo(repo_path)?;
+    let target = resolve_target(repo_path, source, target)?;
+    check_overwrite(repo_path, source, &target, force)?;
+    let config = &config.for_path(source);
+    let mut warnings = run_pre_hook(repo_path, source, Hook::PreMove(target.clone()), config)
+        .map_err(HookFailed)?;
+
+    create_parent_dirs(repo_path, &target)?;
+    fs::rename(repo_path.join(source), repo_path.join(&target)).map_err(io_error(source))?;
+    remove_empty_parent_dirs(repo_path, source);
+
+    warnings.extend(auto_commit(
+        repo_path,
+        config,
+        &[source, &target],
+        &format!(
+            "Move {} to {}",
+            source.to_string_lossy(),
+            target.to_string_lossy()
+        ),
+    ));
+    warnings
+        .extend(run_hook(repo_path, source, Hook::PostMove(target), config).map_err(HookFailed)?);
+    Ok(Put(warnings))
+}
+
+pub fn copy(
+    repo_path: &Path,
+    config: &Config,
+    source: &Path,
+    target: &Path,
+    force: bool,
+) -> Result<Output, Error> {
+    check_repo(repo_path)?;
+    let target = resolve_target(repo_path, source, target)?;
+    check_overwrite(repo_path, source, &target, force)?;
+
+    let copies: Vec<(PathBuf, PathBuf)> = if repo_path.join(source).is_dir() {
+        walk::secrets(repo_path, source)
+            .map_err(io_error(source))?
+            .into_iter()
+            .map(|path| {
+                let copy = target.join(path.strip_prefix(source).unwrap());
+                (path, copy)
+            })
+            .collect()
+    } else {
+        vec![(source.to_path_buf(), target.clone())]
+    };
+
+    let mut warnings = Vec::new();
+    for (_, copy) in copies.iter() {
+        warnings.extend(
+            run_pre_hook(repo_path, copy, Hook::PreSet, &config.for_path(copy))
+                .map_err(HookFailed)?,
+        );
+    }
+    for (path, copy) in copies.iter() {
+        create_parent_dirs(repo_path, copy)?;
+        fs::copy(repo_path.join(path), repo_path.join(copy)).map_err(io_error(path))?;
+    }
+
+    warnings.extend(auto_commit(
+        repo_path,
+        config,
+        &[&target],
+        &format!(
+            "Copy {} to {}",
+            source.to_string_lossy(),
+            target.to_string_lossy()
+        ),
+    ));
+    for (_, copy) in copies.iter() {
+        warnings.extend(
+            run_hook(repo_path, copy, Hook::PostSet, &config.for_path(copy)).map_err(HookFailed)?,
+        );
+    }
+    Ok(Put(warnings))
+}
+
+fn trash_path(repo_path: &Path, path: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut dir = PathBuf::from(TRASH_DIR).join(now.to_string());
+    let mut n = 1;
+    while repo_path.join(&dir).join(path).exists() {
+        dir = PathBuf::from(TRASH_DIR).join(format!("{}-{}", now, n));
+        n += 1;
+    }
+    dir.join(path)
+}
+
+pub fn remove(
+    repo_path: &Path,
+    config: &Config,
+    path: &Path,
+    recursive: bool,
+    trash: bool,
+) -> Result<Output, Error> {
+    check_repo(repo_path)?;
+    check_secret_path(path)?;
+    let config = &config.for_path(path);
+    let full_path = repo_path.join(path);
+    if !full_path.exists() {
+        return Err(FileDoesNotExist(path.to_path_buf()));
+    }
+    if full_path.is_dir() && !recursive {
+        return Err(TargetIsDirectory(path.to_path_buf()));
+    }
+    let mut warnings =
+        run_pre_hook(repo_path, path, Hook::PreRemove, config).map_err(HookFailed)?;
+
+    warnings.extend(if trash {
+        let trash_path = trash_path(repo_path, path);
+        create_parent_dirs(repo_path, &trash_path)?;
+        fs::rename(&full_path, repo_path.join(&trash_path)).map_err(io_error(path))?;
+        remove_empty_parent_dirs(repo_path, path);
+        auto_commit(
+            repo_path,
+            config,
+            &[path, &trash_path],
+            &format!("Move {} to the trash", path.to_string_lossy()),
+        )
+    } else {
+        if full_path.is_dir() {
+            fs::remove_dir_all(&full_path).map_err(io_error(path))?;
+        } else {
+            fs::remove_file(&full_path).map_err(io_error(path))?;
+        }
+        remove_empty_parent_dirs(repo_path, path);
+        auto_commit(
+            repo_path,
+            config,
+            &[path],
+            &format!("Remove {}", path.to_string_lossy()),
+        )
+    });
+
+    warnings.extend(run_hook(repo_path, path, Hook::PostRemove, config).map_err(HookFailed)?);
+    Ok(Put(warnings))
+}
+
+pub fn run_git(repo_path: &Path, args: &[&OsStr]) -> Result<Output, Error> {
+    if !repo_path.join(KEY_FILE).is_file() {
+        return Err(NoRepo);
+    }
+    let status = git::run(repo_path, args).map_err(|e| GitFailed(e.to_string()))?;
+    if status.success() {
+        Ok(NoOutput)
+    } else {
+        Err(GitFailed(format!("git exited with {}", status)))
+    }
+}
+
+/// Asks the user to trust the hooks and the password generator of the
+/// store that are not trusted yet, or have changed since they were trusted
+pub fn trust(repo_path: &Path, config: &Config, yes: bool) -> Result<Output, Error> {
+    check_repo(repo_path)?;
+
+    let mut items = Vec::new();
+    for script in hooks::get_local_scripts(repo_path) {
+        let relative = script.strip_prefix(repo_path).unwrap_or(&script);
+        let contents = fs::read(&script).map_err(io_error(relative))?;
+        items.push((
+            format!("Hook {}", relative.to_string_lossy()),
+            trust::file_key(&script),
+            contents,
+        ));
+    }
+    let mut commands: Vec<(String, &str)> = repo_commands(config)
+        .into_iter()
+        .map(|(_, name, command)| (name, command))
+        .collect();
+    for rule in config.rules.iter().filter(|rule| rule.is_from_repo()) {
+        if let Some(toml::Value::String(command)) = rule.settings.get("password-generator") {
+            commands.push((
+                rule_setting_name(&rule.pattern, "password-generator"),
+                command,
+            ));
+        }
+    }
+    for (name, command) in commands {
+        items.push((
+            format!("{} = {:?} in .sala/config", name, command),
+            trust::command_key(repo_path, &name),
+            command.as_bytes().to_vec(),
+        ));
+    }
+
+    let mut reviewed = 0;
+    for (description, key, contents) in items {
+        let reason = match trust::check(&key, &contents) {
+            Trust::Trusted => continue,
+            Trust::Untrusted => "is not trusted",
+            Trust::Changed => "has changed since it was trusted",
+        };
+        reviewed += 1;
+        message!(config, "{} {}\n", description, reason);
+        if yes
+            || read_line("Trust it? [y/N] ")?
+                .trim()
+                .eq_ignore_ascii_case("y")
+        {
+            trust::trust(&key, &contents)
+                .map_err(|e| IoFailed(trust::store_path().unwrap_or_default(), e))?;
+        }
+    }
+    if reviewed == 0 {
+        message!(config, "Nothing to review\n");
+    }
+    Ok(NoOutput)
+}
+
+/// Returns the config rules, or the ones that apply to `path`
+pub fn rules(config: &Config, path: Option<&Path>) -> Result<Output, Error> {
+    let rules = match path {
+        Some(path) => config.rules_for(path).into_iter().cloned().collect(),
+        None => config.rules.clone(),
+    };
+    Ok(Rules(path.map(Path::to_path_buf), rules))
+}
+
+fn config_error(err: config::Error) -> Error {
+    InvalidConfig(err.path, err.error.to_string())
+}
+
+/// Returns the effective settings and the files they come from
+pub fn config_list(repo_path: &Path) -> Result<Output, Error> {
+    Ok(Settings(config::settings(repo_path).map_err(config_error)?))
+}
+
+pub fn config_get(repo_path: &Path, key: &str) -> Result<Output, Error> {
+    if !config::is_setting(key) {
+        return Err(UnknownSetting(key.to_string()));
+    }
+    let setting = config::settings(repo_path)
+        .map_err(config_error)?
+        .into_iter()
+        .find(|setting| setting.key == key);
+    Ok(Setting(key.to_string(), setting))
+}
+
+/// Sets `key` in the store's or the user's config file, keeping the rest
+/// of the file as it is
+pub fn config_set(
+    repo_path: &Path,
+    target: ConfigTarget,
+    key: &str,
+    value: &str,
+) -> Result<Output, Error> {
+    if !config::is_setting(key) {
+        return Err(UnknownSetting(key.to_string()));
+    }
+    let path = match target {
+        ConfigTarget::Repo if !repo_path.join(KEY_FILE).is_file() => return Err(NoRepo),
+        ConfigTarget::Repo => config::repo_config_path(repo_path),
+        ConfigTarget::User => config::user_config_path().ok_or_else(|| {
+            IoFailed(
+                PathBuf::from("sala.toml"),
+                io::Error::new(io::ErrorKind::NotFound, "No config directory"),
+            )
+        })?,
+    };
+    // A config that doesn't load can only be fixed one setting at a time,
+    // so an error that was already there doesn't prevent the change
+    let old_error = config::load(repo_path).err().map(config_error);
+    let old_contents = match fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(IoFailed(path, err)),
+    };
+    let contents = config::edit(
+        old_contents.as_deref().unwrap_or(""),
+        key,
+        &config::parse_value(value),
+    )
+    .map_err(|e| InvalidConfig(path.clone(), e.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error(dir))?;
+    }
+    fs::write(&path, contents).map_err(io_error(&path))?;
+
+    // Some values can only be checked together with the other settings,
+    // e.g. the cipher depends on the backend
+    let new_config = match config::load(repo_path) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            let err = config_error(err);
+            let broken_before = match old_error {
+                Some(old) => {
+                    old.to_string() == err.to_string() || err.path() != Some(path.as_path())
+                }
+                None => false,
+            };
+            if broken_before {
+                return Ok(NoOutput);
+            }
+            match old_contents {
+                Some(contents) => fs::write(&path, contents),
+                None => fs::remove_file(&path),
+            }
+            .map_err(io_error(&path))?;
+            return Err(err);
+        }
+    };
+
+    match target {
+        ConfigTarget::Repo => Ok(Put(auto_commit(
+            repo_path,
+            &new_config,
+            &[Path::new(".sala/config")],
+            &format!("Set {}", key),
+        ))),
+        ConfigTarget::User => Ok(NoOutput),
+    }
+}
 
-    let hook_warnings = run_hook(&repo_path, path, Hook::PostSet);
-    Ok(Put(hook_warnings))
+pub fn fsck(repo_path: &Path, config: &Config) -> Result<Output, Error> {
+    let crypto = gpg::crypto(&config.backend);
+    let (master_key, warnings) = unlock_repo(repo_path, config, &*crypto)?;
+    let (checked, problems) =
+        fsck::check(repo_path, &*crypto, &master_key).map_err(io_error(repo_path))?;
+    Ok(Checked(checked, problems, warnings))
 }
 
 pub fn get_or_set(
@@ -224,8 +1764,8 @@ pub fn get_or_set(
     raw: bool,
 ) -> Result<Output, Error> {
     if repo_path.join(path).exists() {
-        get(repo_path, path, raw)
+        get_one(repo_path, path, config, raw, false, None)
     } else {
-        set(repo_path, path, config)
+        set(repo_path, path, config, SecretSource::Prompt, false)
     }
 }
@@ -1,11 +1,17 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, SubCommand};
 use sala::{
-    config,
+    config::{self, Format},
+    ConfigTarget,
     Error::{self, *},
     Output::{self, *},
+    RekeyMode, SecretSource,
 };
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::env;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 
 fn main() {
@@ -28,33 +34,340 @@ fn main() {
                 .global(true)
                 .help("Use the password repository in DIR instead of current directory"),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .global(true)
+                .help("Output format, json prints a JSON object for every result and error"),
+        )
+        .arg(
+            Arg::with_name("passphrase-fd")
+                .long("passphrase-fd")
+                .takes_value(true)
+                .value_name("FD")
+                .global(true)
+                .validator(|fd| {
+                    fd.parse::<RawFd>()
+                        .map(|_| ())
+                        .map_err(|_| "must be a file descriptor number".to_string())
+                })
+                .help("Read the master passphrase from file descriptor FD"),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .display_order(0)
-                .about("Initialize a repository"),
+                .about("Initialize a repository")
+                .arg(
+                    Arg::with_name("git")
+                        .long("git")
+                        .help("Also create a git repository with suitable attributes"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .display_order(1)
                 .about("Read a secret")
+                .arg(
+                    Arg::with_name("clip")
+                        .short("c")
+                        .long("clip")
+                        .help("Copy the secret to the clipboard instead of printing it"),
+                )
+                .arg(
+                    Arg::with_name("field")
+                        .short("f")
+                        .long("field")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("Read a single field of a structured secret"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .required(true)
-                        .help("Path of the file to read"),
+                        .multiple(true)
+                        .help("Paths of the files or directories to read"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("set")
                 .display_order(2)
                 .about("Create or update a secret")
+                .arg(
+                    Arg::with_name("generate")
+                        .short("g")
+                        .long("generate")
+                        .help("Store a generated password instead of asking for one"),
+                )
+                .arg(
+                    Arg::with_name("fields")
+                        .short("f")
+                        .long("fields")
+                        .conflicts_with("stdin")
+                        .help("Ask for fields to store after the password"),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .conflicts_with("generate")
+                        .help("Read the secret from stdin as is, without confirmation"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .required(true)
                         .help("Path of the file to write"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("edit")
+                .display_order(3)
+                .about("Edit a secret in $EDITOR")
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .help("Path of the file to edit"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mv")
+                .display_order(4)
+                .about("Move or rename a secret or a directory")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Replace an existing secret"),
+                )
+                .arg(Arg::with_name("source").required(true).help("Path to move"))
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("New path, or a directory to move into"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .display_order(5)
+                .about("Copy a secret or a directory")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Replace an existing secret"),
+                )
+                .arg(Arg::with_name("source").required(true).help("Path to copy"))
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("Path of the copy, or a directory to copy into"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .display_order(6)
+                .about("Remove a secret or a directory")
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("recursive")
+                        .help("Remove a directory and all secrets in it"),
+                )
+                .arg(
+                    Arg::with_name("trash")
+                        .short("t")
+                        .long("trash")
+                        .help("Move to .sala/trash instead of removing for good"),
+                )
+                .arg(Arg::with_name("path").required(true).help("Path to remove")),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .alias("tree")
+                .display_order(7)
+                .about("List secrets")
+                .arg(
+                    Arg::with_name("path")
+                        .help("Directory to list, defaults to the whole repository"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("find")
+                .display_order(8)
+                .about("Find secrets whose path matches a pattern")
+                .arg(
+                    Arg::with_name("regex")
+                        .short("e")
+                        .long("regex")
+                        .help("Treat the pattern as a regular expression instead of a glob"),
+                )
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Match case-insensitively"),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .required(true)
+                        .help("Pattern to match against the paths of secrets"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grep")
+                .display_order(9)
+                .about("Find secrets whose contents match a regular expression")
+                .arg(
+                    Arg::with_name("ignore-case")
+                        .short("i")
+                        .long("ignore-case")
+                        .help("Match case-insensitively"),
+                )
+                .arg(
+                    Arg::with_name("lines")
+                        .long("lines")
+                        .help("Also print the matching lines (i.e. the secrets)"),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .required(true)
+                        .help("Regular expression to match against the decrypted secrets"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .alias("verify")
+                .display_order(10)
+                .about("Check that all secrets decrypt and look for stray files"),
+        )
+        .subcommand(
+            SubCommand::with_name("passwd")
+                .display_order(11)
+                .about("Change the master passphrase"),
+        )
+        .subcommand(
+            SubCommand::with_name("rekey")
+                .display_order(12)
+                .about("Generate a new master key and re-encrypt all secrets with it")
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with("abort")
+                        .help("Continue an interrupted rekey"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .help("Roll back an interrupted rekey"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("agent")
+                .display_order(13)
+                .about("Start an agent that caches the master key")
+                .arg(
+                    Arg::with_name("foreground")
+                        .short("f")
+                        .long("foreground")
+                        .help("Don't detach from the terminal"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lock")
+                .display_order(14)
+                .about("Make the agent forget the master keys"),
+        )
+        .subcommand(
+            SubCommand::with_name("git")
+                .display_order(15)
+                .about("Run git in the password store")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("args")
+                        .multiple(true)
+                        .allow_hyphen_values(true)
+                        .help("Arguments to git"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rules")
+                .display_order(17)
+                .about("Show the config rules, or the ones that apply to PATH")
+                .arg(Arg::with_name("path").help("Path of a secret or a directory")),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .display_order(18)
+                .about("Show or change the configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Show all settings and the files they come from"),
+                )
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Show the value of a setting")
+                        .arg(
+                            Arg::with_name("key")
+                                .required(true)
+                                .help("Name of the setting"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Change a setting in a config file")
+                        .arg(
+                            Arg::with_name("repo")
+                                .long("repo")
+                                .help("Change .sala/config of the repository (default)"),
+                        )
+                        .arg(
+                            Arg::with_name("user")
+                                .long("user")
+                                .conflicts_with("repo")
+                                .help("Change the user's config file"),
+                        )
+                        .arg(
+                            Arg::with_name("key")
+                                .required(true)
+                                .help("Name of the setting"),
+                        )
+                        .arg(
+                            Arg::with_name("value")
+                                .required(true)
+                                .allow_hyphen_values(true)
+                                .help("The new value, in TOML syntax or a plain string"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trust")
+                .display_order(16)
+                .about("Review and trust the hooks and commands of the repository")
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("Trust everything without asking"),
+                ),
+        )
         .arg(Arg::with_name("path").hidden(true))
-        .get_matches();
+        .get_matches_safe()
+        .unwrap_or_else(|err| {
+            if err.use_stderr() {
+                if json_format_requested() {
+                    print_json(&json!({
+                        "type": "error",
+                        "code": Usage.code(),
+                        "message": err.message,
+                    }));
+                } else {
+                    eprintln!("{}", err.message);
+                }
+                std::process::exit(Usage.exit_code());
+            }
+            err.exit()
+        });
 
     let repo_path = PathBuf::from(
         app_m
@@ -64,9 +377,44 @@ fn main() {
             .or_else(|| env::var_os("SALADIR"))
             .unwrap_or(OsString::from(".")),
     );
+    let format = if app_m.value_of("format") == Some("json") {
+        Format::Json
+    } else {
+        Format::Text
+    };
+
+    // The config files are read as they are, so that `sala config` can be
+    // used to fix a config that doesn't load
+    if let ("config", Some(sub_m)) = app_m.subcommand() {
+        let result = match sub_m.subcommand() {
+            ("get", Some(get_m)) => sala::config_get(&repo_path, get_m.value_of("key").unwrap()),
+            ("set", Some(set_m)) => sala::config_set(
+                &repo_path,
+                if set_m.is_present("user") {
+                    ConfigTarget::User
+                } else {
+                    ConfigTarget::Repo
+                },
+                set_m.value_of("key").unwrap(),
+                set_m.value_of("value").unwrap(),
+            ),
+            _ => sala::config_list(&repo_path),
+        };
+        print_result(result, format);
+        return;
+    }
 
-    let config = match config::load(&repo_path) {
+    let mut config = match config::load(&repo_path) {
         Ok(config) => config,
+        Err(err) if format == Format::Json => {
+            print_json(&json!({
+                "type": "error",
+                "code": "invalid_config",
+                "message": err.error.to_string(),
+                "path": path_json(&err.path),
+            }));
+            std::process::exit(1);
+        }
         Err(err) => {
             eprintln!("Error loading config file {}:", err.path.to_string_lossy());
             eprintln!("{}", err.error);
@@ -74,37 +422,148 @@ fn main() {
         }
     };
 
+    config.passphrase_fd = app_m
+        .value_of("passphrase-fd")
+        .map(|fd| fd.parse().unwrap());
+    config.passphrase_file = env::var_os("SALA_PASSPHRASE_FILE").map(PathBuf::from);
+    config.format = format;
+
     let raw = app_m.is_present("raw");
     let result = match (app_m.subcommand(), app_m.value_of_os("path")) {
-        (("init", Some(_)), _) => sala::init(&repo_path, &config),
+        (("init", Some(sub_m)), _) => sala::init(&repo_path, &config, sub_m.is_present("git")),
         (("get", Some(sub_m)), _) => sala::get(
             &repo_path,
-            Path::new(sub_m.value_of_os("path").unwrap()),
+            &sub_m
+                .values_of_os("path")
+                .unwrap()
+                .map(Path::new)
+                .collect::<Vec<&Path>>(),
+            &config,
             raw,
+            sub_m.is_present("clip"),
+            sub_m.value_of("field"),
         ),
         (("set", Some(sub_m)), _) => sala::set(
             &repo_path,
             Path::new(sub_m.value_of_os("path").unwrap()),
             &config,
+            if sub_m.is_present("generate") {
+                SecretSource::Generate
+            } else if sub_m.is_present("stdin") {
+                SecretSource::Stdin
+            } else {
+                SecretSource::Prompt
+            },
+            sub_m.is_present("fields"),
+        ),
+        (("edit", Some(sub_m)), _) => sala::edit(
+            &repo_path,
+            Path::new(sub_m.value_of_os("path").unwrap()),
+            &config,
+            raw,
+        ),
+        (("mv", Some(sub_m)), _) => sala::rename(
+            &repo_path,
+            &config,
+            Path::new(sub_m.value_of_os("source").unwrap()),
+            Path::new(sub_m.value_of_os("target").unwrap()),
+            sub_m.is_present("force"),
+        ),
+        (("cp", Some(sub_m)), _) => sala::copy(
+            &repo_path,
+            &config,
+            Path::new(sub_m.value_of_os("source").unwrap()),
+            Path::new(sub_m.value_of_os("target").unwrap()),
+            sub_m.is_present("force"),
+        ),
+        (("rm", Some(sub_m)), _) => sala::remove(
+            &repo_path,
+            &config,
+            Path::new(sub_m.value_of_os("path").unwrap()),
+            sub_m.is_present("recursive"),
+            sub_m.is_present("trash"),
+        ),
+        (("ls", Some(sub_m)), _) => sala::list(
+            &repo_path,
+            Path::new(sub_m.value_of_os("path").unwrap_or_default()),
+            raw,
+        ),
+        (("find", Some(sub_m)), _) => sala::find(
+            &repo_path,
+            sub_m.value_of("pattern").unwrap(),
+            sub_m.is_present("regex"),
+            sub_m.is_present("ignore-case"),
+            raw,
+        ),
+        (("grep", Some(sub_m)), _) => sala::grep(
+            &repo_path,
+            &config,
+            sub_m.value_of("pattern").unwrap(),
+            sub_m.is_present("ignore-case"),
+            sub_m.is_present("lines"),
+        ),
+        (("fsck", Some(_)), _) => sala::fsck(&repo_path, &config),
+        (("passwd", Some(_)), _) => sala::passwd(&repo_path, &config),
+        (("rekey", Some(sub_m)), _) => sala::rekey(
+            &repo_path,
+            &config,
+            if sub_m.is_present("continue") {
+                RekeyMode::Continue
+            } else if sub_m.is_present("abort") {
+                RekeyMode::Abort
+            } else {
+                RekeyMode::Start
+            },
+        ),
+        (("agent", Some(sub_m)), _) => sala::start_agent(&config, sub_m.is_present("foreground")),
+        (("lock", Some(_)), _) => sala::lock(),
+        (("rules", Some(sub_m)), _) => {
+            sala::rules(&config, sub_m.value_of_os("path").map(Path::new))
+        }
+        (("trust", Some(sub_m)), _) => sala::trust(&repo_path, &config, sub_m.is_present("yes")),
+        (("git", Some(sub_m)), _) => sala::run_git(
+            &repo_path,
+            &sub_m
+                .values_of_os("args")
+                .map(|args| args.collect::<Vec<&OsStr>>())
+                .unwrap_or_default(),
         ),
         (_, Some(path)) => sala::get_or_set(&repo_path, Path::new(path), &config, raw),
         _ => Err(Error::Usage),
     };
+    print_result(result, config.format);
+}
 
-    match result {
-        Ok(output) => {
-            print_output(&output);
+// Prints the output or the error, and exits with a non-zero status if
+// something failed
+fn print_result(result: Result<Output, Error>, format: Format) {
+    match (result, format) {
+        (Ok(output), format) => {
+            if format == Format::Json {
+                print_json(&output_json(&output));
+            } else {
+                print_output(&output);
+            }
+            if output.exit_code() != 0 {
+                std::process::exit(output.exit_code());
+            }
         }
-        Err(error) => {
+        (Err(error), Format::Text) => {
             print_error(&error);
-            std::process::exit(1);
+            std::process::exit(error.exit_code());
+        }
+        (Err(error), Format::Json) => {
+            let mut value = error_json(&error);
+            value["type"] = Value::from("error");
+            print_json(&value);
+            std::process::exit(error.exit_code());
         }
     };
 }
 
-fn maybe_print_hook_warnings(hook_warnings: &Vec<String>) {
-    if hook_warnings.len() > 0 {
-        println!("");
+fn maybe_print_hook_warnings(hook_warnings: &[String]) {
+    if !hook_warnings.is_empty() {
+        println!();
         println!("Hooks produced warnings:");
         for warning in hook_warnings.iter() {
             println!("{}", warning);
@@ -112,59 +571,412 @@ fn maybe_print_hook_warnings(hook_warnings: &Vec<String>) {
     }
 }
 
+#[derive(Default)]
+struct TreeNode {
+    children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn from_paths<'a, I: Iterator<Item = &'a Path>>(paths: I) -> TreeNode {
+        let mut root = TreeNode::default();
+        for path in paths {
+            let mut node = &mut root;
+            for component in path.iter() {
+                node = node
+                    .children
+                    .entry(component.to_string_lossy().to_string())
+                    .or_default();
+            }
+        }
+        root
+    }
+
+    // Following the naming convention, @user files are accounts of the
+    // service (or +group) they are in
+    fn is_account(name: &str, node: &TreeNode) -> bool {
+        name.starts_with('@') && node.children.is_empty()
+    }
+
+    fn label(&self, name: &str) -> String {
+        let accounts: Vec<&str> = self
+            .children
+            .iter()
+            .filter(|(name, node)| TreeNode::is_account(name, node))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if accounts.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}: {}", name, accounts.join(", "))
+        }
+    }
+}
+
+fn print_tree(node: &TreeNode, prefix: &str) {
+    let entries: Vec<(&String, &TreeNode)> = node
+        .children
+        .iter()
+        .filter(|(name, node)| !TreeNode::is_account(name, node))
+        .collect();
+    for (i, (name, child)) in entries.iter().enumerate() {
+        let last = i == entries.len() - 1;
+        println!(
+            "{}{}{}",
+            prefix,
+            if last { "`-- " } else { "|-- " },
+            child.label(name)
+        );
+        print_tree(
+            child,
+            &format!("{}{}", prefix, if last { "    " } else { "|   " }),
+        );
+    }
+}
+
 fn print_output(output: &Output) {
     match output {
-        Get(path, secret, raw, hook_warnings) => {
-            let secret_utf8 = String::from_utf8_lossy(&secret);
+        Get(path, field, secret, raw, hook_warnings) => {
+            let secret_utf8 = String::from_utf8_lossy(secret);
             if *raw {
-                println!("{}", secret_utf8);
+                // Binary secrets are output as is
+                let mut stdout = io::stdout();
+                let _ = stdout
+                    .write_all(secret)
+                    .and_then(|_| stdout.write_all(b"\n"));
             } else {
-                println!("");
-                println!("{}: {}", path.to_string_lossy(), secret_utf8,);
-                maybe_print_hook_warnings(&hook_warnings);
-                println!("");
+                println!();
+                match field {
+                    Some(name) => {
+                        println!("{} ({}): {}", path.to_string_lossy(), name, secret_utf8)
+                    }
+                    None => println!("{}: {}", path.to_string_lossy(), secret_utf8),
+                }
+                maybe_print_hook_warnings(hook_warnings);
+                println!();
             }
         }
-        Put(hook_warnings) => {
-            maybe_print_hook_warnings(&hook_warnings);
+        GetMany(results, raw, hook_warnings) => {
+            // The raw format is like `git cat-file --batch': a header line
+            // with the path and the length of the secret, followed by the
+            // secret and a newline
+            let mut stdout = io::stdout();
+            if !*raw {
+                println!();
+            }
+            for result in results.iter() {
+                match result {
+                    Ok((path, secret)) if *raw => {
+                        let _ = writeln!(stdout, "{} {}", path.to_string_lossy(), secret.len())
+                            .and_then(|_| stdout.write_all(secret))
+                            .and_then(|_| stdout.write_all(b"\n"));
+                    }
+                    Ok((path, secret)) => {
+                        println!(
+                            "{}: {}",
+                            path.to_string_lossy(),
+                            String::from_utf8_lossy(secret)
+                        );
+                    }
+                    Err(error) => {
+                        if *raw {
+                            if let Some(path) = error.path() {
+                                println!("{} error", path.to_string_lossy());
+                            }
+                        }
+                        let _ = stdout.flush();
+                        print_error(error);
+                    }
+                }
+            }
+            if !*raw {
+                maybe_print_hook_warnings(hook_warnings);
+                println!();
+            }
         }
-        NoOutput => {}
-    }
-}
-
-fn print_error(error: &Error) {
-    match error {
-        AlreadyInitialized => {
-            eprintln!("Error: The master key already exists");
+        Copied(path, clear_after, raw, hook_warnings) => {
+            if !*raw {
+                println!();
+                print!("Copied {} to the clipboard", path.to_string_lossy());
+                if *clear_after > 0 {
+                    print!(", clearing in {} seconds", clear_after);
+                }
+                println!();
+                maybe_print_hook_warnings(hook_warnings);
+                println!();
+            }
         }
-        CannotInitRepo => {
-            eprintln!("Error: Failed to initialize a new repository");
+        Put(hook_warnings) => {
+            maybe_print_hook_warnings(hook_warnings);
         }
-        FileDoesNotExist(path) => {
-            eprintln!(
-                "Error: File does not exist or invalid: {}",
-                path.to_string_lossy()
-            );
+        Unchanged(path, raw, hook_warnings) => {
+            if !*raw {
+                println!();
+                println!("No changes to {}", path.to_string_lossy());
+                maybe_print_hook_warnings(hook_warnings);
+                println!();
+            }
         }
-        InputsDidntMatch => {
-            eprintln!("");
-            eprintln!("Inputs did not match.");
+        List(dir, secrets, raw) => {
+            if *raw {
+                for path in secrets.iter() {
+                    println!("{}", path.to_string_lossy());
+                }
+            } else {
+                let tree = TreeNode::from_paths(
+                    secrets
+                        .iter()
+                        .map(|path| path.strip_prefix(dir).unwrap_or(path)),
+                );
+                for (name, node) in tree.children.iter() {
+                    println!("{}", node.label(name));
+                    print_tree(node, "");
+                }
+            }
         }
-        NoRepo => {
-            eprintln!("No repository. Run `sala init' first",);
+        Matches(matches, show_lines, hook_warnings) => {
+            for (path, lines) in matches.iter() {
+                if *show_lines {
+                    for line in lines.iter() {
+                        println!("{}: {}", path.to_string_lossy(), line);
+                    }
+                } else {
+                    println!("{}", path.to_string_lossy());
+                }
+            }
+            maybe_print_hook_warnings(hook_warnings);
         }
-        UnlockFailed => {
-            eprintln!("");
-            eprintln!("Error: Unable to unlock the encryption key");
+        Checked(checked, problems, hook_warnings) => {
+            println!();
+            for (path, problem) in problems.iter() {
+                println!("{}: {}", path.to_string_lossy(), problem);
+            }
+            if problems.is_empty() {
+                println!("Checked {} secrets, no problems found", checked);
+            } else {
+                println!(
+                    "Checked {} secrets, found {} problems",
+                    checked,
+                    problems.len()
+                );
+            }
+            maybe_print_hook_warnings(hook_warnings);
         }
-        Usage => {
-            eprintln!("Try `sala --help'");
+        Rules(path, rules) => {
+            if rules.is_empty() {
+                match path {
+                    Some(path) => println!("No rules apply to {}", path.to_string_lossy()),
+                    None => println!("No rules"),
+                }
+            }
+            for (i, rule) in rules.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                println!(
+                    "[rules.{:?}] in {}",
+                    rule.pattern,
+                    rule.source.to_string_lossy()
+                );
+                print!("{}", rule.settings_toml());
+            }
         }
-        TargetIsDirectory(path) => {
-            eprintln!("Error: Target is a directory: {}", path.to_string_lossy());
+        Settings(settings) => {
+            for setting in settings.iter() {
+                println!(
+                    "{} = {}  # {}",
+                    setting.key,
+                    setting.value,
+                    origin_text(&setting.origin)
+                );
+            }
         }
-        CannotCreateDirectory(path) => {
-            eprintln!("Error: Cannot create directory: {}", path.to_string_lossy());
+        Setting(_, setting) => match setting.as_ref().map(|setting| &setting.value) {
+            Some(toml::Value::String(value)) => println!("{}", value),
+            Some(value) => println!("{}", value),
+            None => {}
+        },
+        NoOutput => {}
+    }
+}
+
+fn origin_text(origin: &Option<PathBuf>) -> String {
+    match origin {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => "default".to_string(),
+    }
+}
+
+fn print_error(error: &Error) {
+    match error {
+        // These come right after a prompt
+        BackendFailed(_)
+        | ClipboardFailed(_)
+        | EditorFailed(_)
+        | FieldDoesNotExist(_, _)
+        | InvalidFieldName(_)
+        | InputFailed(_)
+        | DecryptionFailed(_, _)
+        | EncryptionFailed(_, _)
+        | UnlockFailed
+        | InputsDidntMatch => eprintln!(),
+        _ => {}
+    }
+    match error {
+        InputsDidntMatch | NoRepo | Usage => eprintln!("{}", error),
+        _ => eprintln!("Error: {}", error),
+    }
+}
+
+fn path_json(path: &Path) -> Value {
+    Value::from(path.to_string_lossy())
+}
+
+fn secret_json(object: &mut Map<String, Value>, secret: &[u8]) {
+    // Binary secrets can't be represented as JSON strings
+    match std::str::from_utf8(secret) {
+        Ok(secret) => object.insert("secret".to_string(), Value::from(secret)),
+        Err(_) => object.insert(
+            "secret_base64".to_string(),
+            Value::from(base64::encode(secret)),
+        ),
+    };
+}
+
+fn error_json(error: &Error) -> Value {
+    let mut object = Map::new();
+    object.insert("code".to_string(), Value::from(error.code()));
+    object.insert("message".to_string(), Value::from(error.to_string()));
+    if let Some(path) = error.path() {
+        object.insert("path".to_string(), path_json(path));
+    }
+    if let FieldDoesNotExist(_, name) | InvalidFieldName(name) = error {
+        object.insert("field".to_string(), Value::from(name.as_str()));
+    }
+    Value::Object(object)
+}
+
+fn output_json(output: &Output) -> Value {
+    match output {
+        Get(path, field, secret, _, hook_warnings) => {
+            let mut object = Map::new();
+            object.insert("type".to_string(), Value::from("secret"));
+            object.insert("path".to_string(), path_json(path));
+            object.insert("field".to_string(), json!(field));
+            secret_json(&mut object, secret);
+            object.insert("hook_warnings".to_string(), json!(hook_warnings));
+            Value::Object(object)
+        }
+        GetMany(results, _, hook_warnings) => {
+            let secrets: Vec<Value> = results
+                .iter()
+                .map(|result| match result {
+                    Ok((path, secret)) => {
+                        let mut object = Map::new();
+                        object.insert("path".to_string(), path_json(path));
+                        secret_json(&mut object, secret);
+                        Value::Object(object)
+                    }
+                    Err(error) => json!({
+                        "path": error.path().map(path_json),
+                        "error": error_json(error),
+                    }),
+                })
+                .collect();
+            json!({
+                "type": "secrets",
+                "secrets": secrets,
+                "hook_warnings": hook_warnings,
+            })
         }
+        Copied(path, clear_after, _, hook_warnings) => json!({
+            "type": "copied",
+            "path": path_json(path),
+            "clear_after": clear_after,
+            "hook_warnings": hook_warnings,
+        }),
+        Put(hook_warnings) => json!({
+            "type": "ok",
+            "hook_warnings": hook_warnings,
+        }),
+        Unchanged(path, _, hook_warnings) => json!({
+            "type": "unchanged",
+            "path": path_json(path),
+            "hook_warnings": hook_warnings,
+        }),
+        List(dir, secrets, _) => json!({
+            "type": "list",
+            "directory": path_json(dir),
+            "secrets": secrets.iter().map(|path| path_json(path)).collect::<Vec<Value>>(),
+        }),
+        Matches(matches, _, hook_warnings) => json!({
+            "type": "matches",
+            "matches": matches
+                .iter()
+                .map(|(path, lines)| json!({ "path": path_json(path), "lines": lines }))
+                .collect::<Vec<Value>>(),
+            "hook_warnings": hook_warnings,
+        }),
+        Checked(checked, problems, hook_warnings) => json!({
+            "type": "fsck",
+            "checked": checked,
+            "problems": problems
+                .iter()
+                .map(|(path, problem)| json!({
+                    "path": path_json(path),
+                    "code": problem.code(),
+                    "message": problem.to_string(),
+                }))
+                .collect::<Vec<Value>>(),
+            "hook_warnings": hook_warnings,
+        }),
+        Rules(path, rules) => json!({
+            "type": "rules",
+            "path": path.as_ref().map(|path| path_json(path)),
+            "rules": rules
+                .iter()
+                .map(|rule| json!({
+                    "pattern": rule.pattern,
+                    "source": path_json(&rule.source),
+                    "settings": rule.settings,
+                }))
+                .collect::<Vec<Value>>(),
+        }),
+        Settings(settings) => json!({
+            "type": "config",
+            "settings": settings
+                .iter()
+                .map(|setting| json!({
+                    "key": setting.key,
+                    "value": setting.value,
+                    "origin": setting.origin.as_ref().map(|path| path_json(path)),
+                }))
+                .collect::<Vec<Value>>(),
+        }),
+        Setting(key, setting) => json!({
+            "type": "setting",
+            "key": key,
+            "value": setting.as_ref().map(|setting| &setting.value),
+            "origin": setting
+                .as_ref()
+                .and_then(|setting| setting.origin.as_ref())
+                .map(|path| path_json(path)),
+        }),
+        NoOutput => json!({
+            "type": "ok",
+            "hook_warnings": [],
+        }),
     }
 }
+
+// The arguments couldn't be parsed, so look for --format json by hand
+fn json_format_requested() -> bool {
+    let args: Vec<OsString> = env::args_os().collect();
+    args.iter().any(|arg| arg == "--format=json")
+        || args
+            .windows(2)
+            .any(|pair| pair[0] == "--format" && pair[1] == "json")
+}
+
+fn print_json(value: &Value) {
+    println!("{}", value);
+}
@@ -0,0 +1,558 @@
+use aes::{Aes128, Aes192, Aes256};
+use camellia::{Camellia128, Camellia192, Camellia256};
+use cast5::Cast5;
+use cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use digest::DynDigest;
+use rand::{rngs::OsRng, RngCore};
+use sha1::{Digest, Sha1};
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use twofish::Twofish;
+
+use crate::gpg::{self, Crypto, GnuPG, GpgError};
+
+// Subset of OpenPGP (RFC 4880) needed for reading and writing armored
+// messages that are symmetrically encrypted with a passphrase, i.e. a
+// symmetric-key encrypted session key packet (SKESK) followed by a
+// symmetrically encrypted integrity protected data packet (SEIPD). This
+// is what `gpg --symmetric` produces. Messages that use anything else,
+// like the AEAD packets of newer GnuPG versions, are decrypted by running
+// the gpg binary.
+
+const TAG_SKESK: u8 = 3;
+const TAG_COMPRESSED: u8 = 8;
+const TAG_MARKER: u8 = 10;
+const TAG_LITERAL: u8 = 11;
+const TAG_SEIPD: u8 = 18;
+const TAG_MDC: u8 = 19;
+
+const HASH_SHA1: u8 = 2;
+const HASH_SHA256: u8 = 8;
+
+// Iteration count for newly created messages, in the one-octet coded form
+// of RFC 4880, section 3.7.1.3. 0xE0 means 16 MiB of hashed data.
+const S2K_CODED_COUNT: u8 = 0xE0;
+
+// Compressed data is not inflated beyond this size, so that a small
+// message can't exhaust the memory
+const MAX_DECOMPRESSED_SIZE: usize = 64 << 20;
+
+const ARMOR_BEGIN: &str = "-----BEGIN PGP MESSAGE-----";
+const ARMOR_END: &str = "-----END PGP MESSAGE-----";
+
+fn invalid(msg: &str) -> GpgError {
+    GpgError::OperationFailed(format!("invalid OpenPGP message: {}", msg))
+}
+
+fn unsupported(msg: &str) -> GpgError {
+    GpgError::Unsupported(format!("unsupported OpenPGP {}", msg))
+}
+
+// Symmetric ciphers
+
+#[derive(Clone, Copy)]
+enum SymmetricAlgorithm {
+    Cast5,
+    Aes128,
+    Aes192,
+    Aes256,
+    Twofish,
+    Camellia128,
+    Camellia192,
+    Camellia256,
+}
+
+use SymmetricAlgorithm::*;
+
+const SYMMETRIC_ALGORITHMS: [SymmetricAlgorithm; 8] = [
+    Cast5,
+    Aes128,
+    Aes192,
+    Aes256,
+    Twofish,
+    Camellia128,
+    Camellia192,
+    Camellia256,
+];
+
+trait BlockCipher {
+    fn block_size(&self) -> usize;
+    fn encrypt_block(&self, block: &mut [u8]);
+}
+
+impl<C: BlockEncrypt> BlockCipher for C {
+    fn block_size(&self) -> usize {
+        C::block_size()
+    }
+
+    fn encrypt_block(&self, block: &mut [u8]) {
+        BlockEncrypt::encrypt_block(self, GenericArray::from_mut_slice(block))
+    }
+}
+
+impl SymmetricAlgorithm {
+    fn from_id(id: u8) -> Option<SymmetricAlgorithm> {
+        SYMMETRIC_ALGORITHMS.iter().cloned().find(|a| a.id() == id)
+    }
+
+    // Accepts the same names as gpg's --cipher-algo
+    fn from_name(name: &str) -> Option<SymmetricAlgorithm> {
+        let normalized = gpg::cipher_name(name);
+        SYMMETRIC_ALGORITHMS
+            .iter()
+            .cloned()
+            .find(|a| a.name() == normalized)
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Cast5 => 3,
+            Aes128 => 7,
+            Aes192 => 8,
+            Aes256 => 9,
+            Twofish => 10,
+            Camellia128 => 11,
+            Camellia192 => 12,
+            Camellia256 => 13,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Cast5 => "CAST5",
+            Aes128 => "AES128",
+            Aes192 => "AES192",
+            Aes256 => "AES256",
+            Twofish => "TWOFISH",
+            Camellia128 => "CAMELLIA128",
+            Camellia192 => "CAMELLIA192",
+            Camellia256 => "CAMELLIA256",
+        }
+    }
+
+    fn key_size(self) -> usize {
+        match self {
+            Cast5 | Aes128 | Camellia128 => 16,
+            Aes192 | Camellia192 => 24,
+            Aes256 | Twofish | Camellia256 => 32,
+        }
+    }
+
+    fn block_cipher(self, key: &[u8]) -> Result<Box<dyn BlockCipher>, GpgError> {
+        fn new<C: BlockEncrypt + KeyInit + 'static>(
+            key: &[u8],
+        ) -> Result<Box<dyn BlockCipher>, GpgError> {
+            C::new_from_slice(key)
+                .map(|c| Box::new(c) as Box<dyn BlockCipher>)
+                .map_err(|_| invalid("bad session key length"))
+        }
+        match self {
+            Cast5 => new::<Cast5>(key),
+            Aes128 => new::<Aes128>(key),
+            Aes192 => new::<Aes192>(key),
+            Aes256 => new::<Aes256>(key),
+            Twofish => new::<Twofish>(key),
+            Camellia128 => new::<Camellia128>(key),
+            Camellia192 => new::<Camellia192>(key),
+            Camellia256 => new::<Camellia256>(key),
+        }
+    }
+}
+
+// CFB mode with an all-zero IV, as used by SEIPD and encrypted session keys
+fn cfb_encrypt(cipher: &dyn BlockCipher, data: &mut [u8]) {
+    let mut feedback = vec![0u8; cipher.block_size()];
+    for chunk in data.chunks_mut(feedback.len()) {
+        cipher.encrypt_block(&mut feedback);
+        for (byte, mask) in chunk.iter_mut().zip(feedback.iter()) {
+            *byte ^= mask;
+        }
+        feedback[..chunk.len()].copy_from_slice(chunk);
+    }
+}
+
+fn cfb_decrypt(cipher: &dyn BlockCipher, data: &mut [u8]) {
+    let mut feedback = vec![0u8; cipher.block_size()];
+    for chunk in data.chunks_mut(feedback.len()) {
+        let mut mask = feedback.clone();
+        cipher.encrypt_block(&mut mask);
+        feedback[..chunk.len()].copy_from_slice(chunk);
+        for (byte, mask) in chunk.iter_mut().zip(mask.iter()) {
+            *byte ^= mask;
+        }
+    }
+}
+
+// String-to-key specifiers
+
+fn hasher(id: u8) -> Option<Box<dyn DynDigest>> {
+    match id {
+        HASH_SHA1 => Some(Box::new(Sha1::new())),
+        HASH_SHA256 => Some(Box::new(Sha256::new())),
+        9 => Some(Box::new(Sha384::new())),
+        10 => Some(Box::new(Sha512::new())),
+        11 => Some(Box::new(Sha224::new())),
+        _ => None,
+    }
+}
+
+enum S2k {
+    Simple(u8),
+    Salted(u8, [u8; 8]),
+    Iterated(u8, [u8; 8], u8),
+}
+
+impl S2k {
+    fn parse(data: &[u8]) -> Result<(S2k, usize), GpgError> {
+        let salt = |data: &[u8]| {
+            let mut salt = [0u8; 8];
+            salt.copy_from_slice(&data[2..10]);
+            salt
+        };
+        match data {
+            [0, hash, ..] => Ok((S2k::Simple(*hash), 2)),
+            [1, hash, ..] if data.len() >= 10 => Ok((S2k::Salted(*hash, salt(data)), 10)),
+            [3, hash, ..] if data.len() >= 11 => {
+                Ok((S2k::Iterated(*hash, salt(data), data[10]), 11))
+            }
+            _ => Err(unsupported("string-to-key specifier")),
+        }
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        match self {
+            S2k::Simple(hash) => vec![0, *hash],
+            S2k::Salted(hash, salt) => [&[1, *hash][..], salt].concat(),
+            S2k::Iterated(hash, salt, count) => [&[3, *hash][..], salt, &[*count]].concat(),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &[u8], key_size: usize) -> Result<Vec<u8>, GpgError> {
+        let (hash, salt, count): (u8, &[u8], usize) = match self {
+            S2k::Simple(hash) => (*hash, &[], 0),
+            S2k::Salted(hash, salt) => (*hash, salt, 0),
+            S2k::Iterated(hash, salt, coded) => (
+                *hash,
+                salt,
+                (16 + (*coded as usize & 15)) << ((*coded as usize >> 4) + 6),
+            ),
+        };
+        let input = [salt, passphrase].concat();
+        let count = count.max(input.len());
+
+        let mut key = Vec::new();
+        let mut preload = 0;
+        while key.len() < key_size {
+            let mut h = hasher(hash).ok_or_else(|| unsupported("hash algorithm"))?;
+            h.update(&vec![0; preload]);
+            let mut remaining = count;
+            while remaining >= input.len() {
+                h.update(&input);
+                remaining -= input.len();
+            }
+            h.update(&input[..remaining]);
+            key.extend_from_slice(&h.finalize());
+            preload += 1;
+        }
+        key.truncate(key_size);
+        Ok(key)
+    }
+}
+
+// Packet framing
+
+fn read_packet(data: &[u8]) -> Result<(u8, Vec<u8>, &[u8]), GpgError> {
+    let truncated = || invalid("truncated packet");
+    let ctb = *data.first().ok_or_else(truncated)?;
+    if ctb & 0x80 == 0 {
+        return Err(invalid("bad packet header"));
+    }
+
+    if ctb & 0x40 == 0 {
+        // Old format
+        let tag = (ctb >> 2) & 0x0f;
+        let (len, header) = match ctb & 3 {
+            0 => (*data.get(1).ok_or_else(truncated)? as usize, 2),
+            1 => (be_uint(data.get(1..3).ok_or_else(truncated)?), 3),
+            2 => (be_uint(data.get(1..5).ok_or_else(truncated)?), 5),
+            _ => (data.len() - 1, 1),
+        };
+        let body = data.get(header..header + len).ok_or_else(truncated)?;
+        return Ok((tag, body.to_vec(), &data[header + len..]));
+    }
+
+    // New format, possibly split into partial body chunks
+    let tag = ctb & 0x3f;
+    let mut body = Vec::new();
+    let mut rest = &data[1..];
+    loop {
+        let first = *rest.first().ok_or_else(truncated)? as usize;
+        let (len, header, partial) = match first {
+            0..=191 => (first, 1, false),
+            192..=223 => {
+                let second = *rest.get(1).ok_or_else(truncated)? as usize;
+                (((first - 192) << 8) + second + 192, 2, false)
+            }
+            255 => (be_uint(rest.get(1..5).ok_or_else(truncated)?), 5, false),
+            _ => (1 << (first & 0x1f), 1, true),
+        };
+        body.extend_from_slice(rest.get(header..header + len).ok_or_else(truncated)?);
+        rest = &rest[header + len..];
+        if !partial {
+            return Ok((tag, body, rest));
+        }
+    }
+}
+
+fn write_packet(out: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    let len = body.len();
+    out.push(0xc0 | tag);
+    if len < 192 {
+        out.push(len as u8);
+    } else if len < 8384 {
+        out.push((((len - 192) >> 8) + 192) as u8);
+        out.push((len - 192) as u8);
+    } else {
+        out.push(255);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(body);
+}
+
+fn be_uint(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+}
+
+// ASCII armor
+
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0x00b7_04ce;
+    for &byte in data {
+        crc ^= u32::from(byte) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x0100_0000 != 0 {
+                crc ^= 0x0186_4cfb;
+            }
+        }
+    }
+    crc & 0x00ff_ffff
+}
+
+fn armor(data: &[u8]) -> Vec<u8> {
+    let encoded = base64::encode(data);
+    let mut result = format!("{}\n\n", ARMOR_BEGIN);
+    for line in encoded.as_bytes().chunks(64) {
+        result.push_str(&String::from_utf8_lossy(line));
+        result.push('\n');
+    }
+    result.push('=');
+    result.push_str(&base64::encode(&crc24(data).to_be_bytes()[1..]));
+    result.push('\n');
+    result.push_str(ARMOR_END);
+    result.push('\n');
+    result.into_bytes()
+}
+
+fn dearmor(message: &[u8]) -> Result<Vec<u8>, GpgError> {
+    let text = String::from_utf8_lossy(message);
+    let mut lines = text
+        .lines()
+        .map(str::trim_end)
+        .skip_while(|line| *line != ARMOR_BEGIN);
+    if lines.next().is_none() {
+        // Not armored, assume a binary message
+        return Ok(message.to_vec());
+    }
+
+    // Skip the armor headers
+    let mut lines = lines.skip_while(|line| !line.is_empty()).skip(1);
+
+    let mut encoded = String::new();
+    let mut checksum = None;
+    for line in &mut lines {
+        if line == ARMOR_END {
+            break;
+        } else if let Some(crc) = line.strip_prefix('=') {
+            checksum = Some(crc);
+        } else {
+            encoded.push_str(line);
+        }
+    }
+
+    let data = base64::decode(&encoded).map_err(|_| invalid("bad armor"))?;
+    if let Some(checksum) = checksum {
+        let expected = base64::decode(checksum).map_err(|_| invalid("bad armor checksum"))?;
+        if expected[..] != crc24(&data).to_be_bytes()[1..] {
+            return Err(invalid("armor checksum mismatch"));
+        }
+    }
+    Ok(data)
+}
+
+// Messages
+
+fn decrypt_seipd(
+    algo: SymmetricAlgorithm,
+    key: &[u8],
+    body: &[u8],
+) -> Result<Option<Vec<u8>>, GpgError> {
+    if body.first() != Some(&1) {
+        return Err(unsupported("encrypted data packet version"));
+    }
+    let cipher = algo.block_cipher(key)?;
+    let bs = cipher.block_size();
+
+    let mut plaintext = body[1..].to_vec();
+    cfb_decrypt(&*cipher, &mut plaintext);
+
+    // The random prefix repeats its last two octets, this catches most
+    // wrong keys before checking the MDC
+    if plaintext.len() < bs + 2 + 22 || plaintext[bs - 2..bs] != plaintext[bs..bs + 2] {
+        return Ok(None);
+    }
+
+    let (data, mdc) = plaintext.split_at(plaintext.len() - 20);
+    if data[data.len() - 2..] != [0xc0 | TAG_MDC, 20] || Sha1::digest(data)[..] != *mdc {
+        return Ok(None);
+    }
+    Ok(Some(data[bs + 2..data.len() - 2].to_vec()))
+}
+
+fn literal_data(mut packets: &[u8], compressed: bool) -> Result<Vec<u8>, GpgError> {
+    use miniz_oxide::inflate::{decompress_to_vec_with_limit, decompress_to_vec_zlib_with_limit};
+
+    while !packets.is_empty() {
+        let (tag, body, rest) = read_packet(packets)?;
+        match tag {
+            // gpg never compresses twice, and a nested packet could inflate
+            // to itself
+            TAG_COMPRESSED if compressed => return Err(invalid("nested compressed data")),
+            TAG_COMPRESSED => {
+                let inflated = match body.first() {
+                    Some(0) => Ok(body[1..].to_vec()),
+                    Some(1) => decompress_to_vec_with_limit(&body[1..], MAX_DECOMPRESSED_SIZE),
+                    Some(2) => decompress_to_vec_zlib_with_limit(&body[1..], MAX_DECOMPRESSED_SIZE),
+                    _ => return Err(unsupported("compression algorithm")),
+                }
+                .map_err(|_| invalid("bad or too large compressed data"))?;
+                return literal_data(&inflated, true);
+            }
+            TAG_LITERAL => {
+                let name_len = *body.get(1).ok_or_else(|| invalid("bad literal data"))?;
+                let start = 2 + name_len as usize + 4;
+                return body
+                    .get(start..)
+                    .map(|d| d.to_vec())
+                    .ok_or_else(|| invalid("bad literal data"));
+            }
+            _ => packets = rest,
+        }
+    }
+    Err(invalid("no literal data"))
+}
+
+/// Reads and writes passphrase-encrypted messages in-process
+pub struct Native;
+
+impl Native {
+    fn decrypt_message(&self, message: &[u8], key: &[u8]) -> Result<Vec<u8>, GpgError> {
+        let data = dearmor(message)?;
+
+        let mut session_keys = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let (tag, body, next) = read_packet(rest)?;
+            rest = next;
+            match tag {
+                TAG_MARKER => {}
+                TAG_SKESK => {
+                    if body.len() < 2 || body[0] != 4 {
+                        return Err(unsupported("session key packet version"));
+                    }
+                    let algo = SymmetricAlgorithm::from_id(body[1])
+                        .ok_or_else(|| unsupported("cipher algorithm"))?;
+                    let (s2k, s2k_len) = S2k::parse(&body[2..])?;
+                    let s2k_key = s2k.derive_key(key, algo.key_size())?;
+                    let encrypted_key = &body[2 + s2k_len..];
+                    if encrypted_key.is_empty() {
+                        session_keys.push((algo, s2k_key));
+                    } else {
+                        let mut decrypted = encrypted_key.to_vec();
+                        cfb_decrypt(&*algo.block_cipher(&s2k_key)?, &mut decrypted);
+                        if let Some(session_algo) = SymmetricAlgorithm::from_id(decrypted[0]) {
+                            session_keys.push((session_algo, decrypted[1..].to_vec()));
+                        }
+                    }
+                }
+                TAG_SEIPD => {
+                    for (algo, session_key) in session_keys.iter() {
+                        if session_key.len() != algo.key_size() {
+                            continue;
+                        }
+                        if let Some(packets) = decrypt_seipd(*algo, session_key, &body)? {
+                            return literal_data(&packets, false);
+                        }
+                    }
+                    return Err(GpgError::OperationFailed(
+                        "decryption failed: Bad session key".into(),
+                    ));
+                }
+                _ => return Err(unsupported("packet type")),
+            }
+        }
+        Err(invalid("no encrypted data"))
+    }
+}
+
+impl Crypto for Native {
+    fn decrypt(&self, message: &[u8], key: &[u8]) -> Result<Vec<u8>, GpgError> {
+        match self.decrypt_message(message, key) {
+            Err(GpgError::Unsupported(_)) => GnuPG.decrypt(message, key),
+            result => result,
+        }
+    }
+
+    fn encrypt(&self, data: &[u8], key: &[u8], cipher: &str) -> Result<Vec<u8>, GpgError> {
+        let algo = SymmetricAlgorithm::from_name(cipher).ok_or_else(|| {
+            GpgError::OperationFailed(format!("invalid cipher algorithm: {}", cipher))
+        })?;
+        let mut rng = OsRng::new().map_err(|_| {
+            GpgError::OperationFailed("cannot initialize random number generator".into())
+        })?;
+
+        let mut salt = [0u8; 8];
+        rng.fill_bytes(&mut salt);
+        let s2k = S2k::Iterated(HASH_SHA256, salt, S2K_CODED_COUNT);
+        let session_key = s2k.derive_key(key, algo.key_size())?;
+        let cipher = algo.block_cipher(&session_key)?;
+        let bs = cipher.block_size();
+
+        let mut literal = vec![b'b', 0, 0, 0, 0, 0];
+        literal.extend_from_slice(data);
+
+        let mut plaintext = vec![0u8; bs + 2];
+        rng.fill_bytes(&mut plaintext[..bs]);
+        plaintext[bs] = plaintext[bs - 2];
+        plaintext[bs + 1] = plaintext[bs - 1];
+        write_packet(&mut plaintext, TAG_LITERAL, &literal);
+        plaintext.extend_from_slice(&[0xc0 | TAG_MDC, 20]);
+        let mdc = Sha1::digest(&plaintext);
+        plaintext.extend_from_slice(&mdc);
+        cfb_encrypt(&*cipher, &mut plaintext);
+
+        let mut message = Vec::new();
+        let skesk = [&[4, algo.id()][..], &s2k.serialize()].concat();
+        write_packet(&mut message, TAG_SKESK, &skesk);
+        write_packet(&mut message, TAG_SEIPD, &[&[1][..], &plaintext].concat());
+        Ok(armor(&message))
+    }
+
+    fn ciphers(&self) -> Option<Vec<String>> {
+        Some(
+            SYMMETRIC_ALGORITHMS
+                .iter()
+                .map(|a| a.name().to_string())
+                .collect(),
+        )
+    }
+}
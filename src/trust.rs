@@ -0,0 +1,78 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Anyone who can write to a store, e.g. by pushing to a shared git
+// repository, could otherwise make sala run arbitrary commands. Hooks and
+// commands that come from the store are only run after the user has
+// trusted their exact contents.
+
+pub enum Trust {
+    Trusted,
+    Untrusted,
+    Changed,
+}
+
+/// Returns the path of the file that records the trusted items
+pub fn store_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("sala").join("trusted"))
+}
+
+fn hash(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// One "HASH KEY" line per trusted item
+fn read_store() -> Vec<(String, String)> {
+    store_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(hash, key)| (hash.to_string(), key.to_string()))
+        .collect()
+}
+
+/// Returns the key that a hook script in the store is trusted with
+pub fn file_key(path: &Path) -> String {
+    path.canonicalize()
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Returns the key that a command in the store's config, e.g. the
+/// password generator, is trusted with
+pub fn command_key(repo_path: &Path, setting: &str) -> String {
+    format!("{}:{}", setting, file_key(repo_path))
+}
+
+pub fn check(key: &str, contents: &[u8]) -> Trust {
+    match read_store().into_iter().find(|(_, k)| k == key) {
+        None => Trust::Untrusted,
+        Some((h, _)) if h == hash(contents) => Trust::Trusted,
+        Some(_) => Trust::Changed,
+    }
+}
+
+/// Trusts `contents` for `key`, replacing what was trusted before
+pub fn trust(key: &str, contents: &[u8]) -> io::Result<()> {
+    let mut entries = read_store();
+    entries.retain(|(_, k)| k != key);
+    entries.push((hash(contents), key.to_string()));
+
+    let path = store_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents: String = entries
+        .iter()
+        .map(|(hash, key)| format!("{} {}\n", hash, key))
+        .collect();
+    fs::write(path, contents)
+}
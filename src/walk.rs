@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// Dotfiles (.sala, .git, ...) and leftovers of interrupted writes are not
+// secrets
+fn is_secret_name(name: &str) -> bool {
+    !name.starts_with('.') && !name.ends_with(".tmp")
+}
+
+/// Returns false for paths that point inside .sala or other hidden
+/// directories
+pub fn is_visible(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(name) => is_secret_name(&name.to_string_lossy()),
+        _ => true,
+    })
+}
+
+fn walk(repo_path: &Path, dir: &Path, result: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(repo_path.join(dir))? {
+        let entry = entry?;
+        if !is_secret_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let path = dir.join(entry.file_name());
+        let metadata = fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            walk(repo_path, &path, result)?;
+        } else if metadata.is_file() {
+            result.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the paths of all secrets under `dir`, relative to the
+/// repository root and sorted
+pub fn secrets(repo_path: &Path, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    walk(repo_path, dir, &mut result)?;
+    result.sort();
+    Ok(result)
+}
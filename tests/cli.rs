@@ -1,3 +1,4 @@
+use assert_cmd::cargo::cargo_bin;
 use assert_cmd::prelude::*;
 use copy_dir::copy_dir;
 use predicates::prelude::*;
@@ -6,8 +7,11 @@ use std::error::Error;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
 use tempfile::{tempdir, TempDir};
 
 // helpers
@@ -32,15 +36,21 @@ impl TempRepo {
     }
 }
 
-fn run_test<T>(test_fn: T) -> Result<(), Box<Error>>
+fn sala(home: &Path) -> Result<Command, Box<dyn Error>> {
+    let mut cmd = Command::cargo_bin("sala")?;
+    cmd.env("HOME", home)
+        .env("SALA_AGENT_SOCK", home.join("agent/agent.sock"));
+    Ok(cmd)
+}
+
+fn run_test<T>(test_fn: T) -> Result<(), Box<dyn Error>>
 where
-    T: FnOnce(&mut Command, &Path, &TempRepo) -> Result<(), Box<Error>>,
+    T: FnOnce(&mut Command, &Path, &TempRepo) -> Result<(), Box<dyn Error>>,
 {
     let empty_dir = tempdir()?;
     let repo = TempRepo::new()?;
-    let mut cmd = Command::cargo_bin("sala")?;
-    cmd.env("HOME", empty_dir.path());
-    test_fn(&mut cmd, &empty_dir.path(), &repo)
+    let mut cmd = sala(empty_dir.path())?;
+    test_fn(&mut cmd, empty_dir.path(), &repo)
 }
 
 const DIRECTORY: &str = "foo";
@@ -52,14 +62,14 @@ const NON_EXISTING_SECRET_DEEP: &str = "foo/bar/baz/@new";
 // get
 
 #[test]
-fn get_no_repo() -> Result<(), Box<Error>> {
+fn get_no_repo() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, dir, _| {
         fs::write(dir.join("foo"), "".as_bytes())?;
 
-        cmd.current_dir(&dir)
-            .args(&["get", "foo"])
+        cmd.current_dir(dir)
+            .args(["get", "foo"])
             .assert()
-            .failure()
+            .code(3)
             .stderr(similar("No repository. Run `sala init' first\n"));
 
         Ok(())
@@ -67,15 +77,15 @@ fn get_no_repo() -> Result<(), Box<Error>> {
 }
 
 #[test]
-fn get_wrong_passphrase() -> Result<(), Box<Error>> {
+fn get_wrong_passphrase() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["get", EXISTING_SECRET])
+            .args(["get", EXISTING_SECRET])
             .with_stdin()
             .buffer("this is wrong\n")
             .output()?
             .assert()
-            .failure()
+            .code(5)
             .stderr(similar(
                 "\
 Enter the master passphrase: 
@@ -88,12 +98,12 @@ Error: Unable to unlock the encryption key
 }
 
 #[test]
-fn get_not_found() -> Result<(), Box<Error>> {
+fn get_not_found() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["get", NON_EXISTING_SECRET])
+            .args(["get", NON_EXISTING_SECRET])
             .assert()
-            .failure()
+            .code(4)
             .stderr(similar(format!(
                 "\
 Error: File does not exist or invalid: {}
@@ -106,28 +116,145 @@ Error: File does not exist or invalid: {}
 }
 
 #[test]
-fn get_directory() -> Result<(), Box<Error>> {
+fn get_corrupt() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
+        fs::write(repo.path().join("foo/@corrupt"), "this is not encrypted")?;
         cmd.current_dir(repo.path())
-            .args(&["get", DIRECTORY])
+            .args(["get", "foo/@corrupt"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
             .assert()
-            .failure()
-            .stderr(similar(format!(
+            .code(6)
+            .stderr(similar(
                 "\
-Error: File does not exist or invalid: {}
+Enter the master passphrase: 
+Error: Unable to decrypt foo/@corrupt: invalid OpenPGP message: bad packet header
 ",
-                DIRECTORY
-            )));
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        fs::copy(
+            repo.path().join(EXISTING_SECRET),
+            repo.path().join(NON_EXISTING_SECRET),
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["get", DIRECTORY])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar("Enter the master passphrase: "))
+            .stdout(similar(
+                "
+foo/@bar: baz
+foo/@new: baz
+
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_hidden_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["get", ".sala"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: File does not exist or invalid: .sala\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_many() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::create_dir(repo.path().join("other"))?;
+        fs::copy(
+            repo.path().join(EXISTING_SECRET),
+            repo.path().join("other/@bar"),
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["get", "other/@bar", NON_EXISTING_SECRET, EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Enter the master passphrase: Error: File does not exist or invalid: foo/@new\n",
+            ))
+            .stdout(similar(
+                "
+other/@bar: baz
+foo/@bar: baz
+
+",
+            ));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", "other", NON_EXISTING_SECRET, DIRECTORY])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .failure()
+            .stdout(similar(
+                "other/@bar 3\nbaz\nfoo/@new error\nfoo/@bar 3\nbaz\n",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_many_none_exist() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["get", NON_EXISTING_SECRET, "foo/@other"])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: File does not exist or invalid: foo/@new\n\
+                 Error: File does not exist or invalid: foo/@other\n",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_many_clip() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["get", "--clip", DIRECTORY])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: Only a single secret can be copied to the clipboard\n",
+            ));
 
         Ok(())
     })
 }
 
 #[test]
-fn get_success() -> Result<(), Box<Error>> {
+fn get_success() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["get", EXISTING_SECRET])
+            .args(["get", EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\n")
             .output()?
@@ -146,10 +273,10 @@ foo/@bar: baz
 }
 
 #[test]
-fn get_raw() -> Result<(), Box<Error>> {
+fn get_raw() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["-r", "get", EXISTING_SECRET])
+            .args(["-r", "get", EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\n")
             .output()?
@@ -162,8 +289,96 @@ fn get_raw() -> Result<(), Box<Error>> {
     })
 }
 
+fn set_structured_secret(home: &Path, repo: &TempRepo) -> Result<(), Box<dyn Error>> {
+    sala(home)?
+        .current_dir(repo.path())
+        .args(["set", "-f", NON_EXISTING_SECRET])
+        .with_stdin()
+        .buffer("qwerty\nhunter2\nhunter2\nusername\nalice\ncodes\n1234\\\n5678\n\n")
+        .output()?
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn get_field() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        set_structured_secret(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["get", "--field", "username", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                "
+foo/@new (username): alice
+
+",
+            ));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", "-f", "codes", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("1234\n5678\n"));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", "-f", "password", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("hunter2\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_field_plain_secret() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", "--field", "password", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_field_does_not_exist() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["get", "--field", "username", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Enter the master passphrase: \nError: No field username in foo/@bar\n",
+            ));
+
+        Ok(())
+    })
+}
+
 #[test]
-fn implicit_get() -> Result<(), Box<Error>> {
+fn implicit_get() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
             .arg(EXISTING_SECRET)
@@ -185,13 +400,10 @@ foo/@bar: baz
 }
 
 #[test]
-fn get_in_dir_does_not_exist() -> Result<(), Box<Error>> {
+fn get_in_dir_does_not_exist() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, tmpdir, _| {
         let dir = tmpdir.join("foo");
-        cmd.args(&["-C", &dir.to_string_lossy(), "get", EXISTING_SECRET])
-            .with_stdin()
-            .buffer("qwerty\n")
-            .output()?
+        cmd.args(["-C", &dir.to_string_lossy(), "get", EXISTING_SECRET])
             .assert()
             .failure()
             .stderr(similar(format!(
@@ -206,9 +418,9 @@ Error: File does not exist or invalid: {}
 }
 
 #[test]
-fn get_in_dir_success() -> Result<(), Box<Error>> {
+fn get_in_dir_success() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
-        cmd.args(&["-C", &repo.path_string(), "get", EXISTING_SECRET])
+        cmd.args(["-C", &repo.path_string(), "get", EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\n")
             .output()?
@@ -227,10 +439,10 @@ foo/@bar: baz
 }
 
 #[test]
-fn get_saladir_env() -> Result<(), Box<Error>> {
+fn get_saladir_env() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.env("SALADIR", repo.path_string())
-            .args(&["get", EXISTING_SECRET])
+            .args(["get", EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\n")
             .output()?
@@ -251,10 +463,10 @@ foo/@bar: baz
 // set
 
 #[test]
-fn set_no_repo() -> Result<(), Box<Error>> {
+fn set_no_repo() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, dir, _| {
-        cmd.current_dir(&dir)
-            .args(&["set", "foobar"])
+        cmd.current_dir(dir)
+            .args(["set", "foobar"])
             .assert()
             .failure()
             .stderr(similar("No repository. Run `sala init' first").trim());
@@ -264,10 +476,10 @@ fn set_no_repo() -> Result<(), Box<Error>> {
 }
 
 #[test]
-fn set_wrong_passphrase() -> Result<(), Box<Error>> {
+fn set_wrong_passphrase() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", NON_EXISTING_SECRET])
+            .args(["set", NON_EXISTING_SECRET])
             .with_stdin()
             .buffer("this is wrong\n")
             .output()?
@@ -285,10 +497,10 @@ Error: Unable to unlock the encryption key
 }
 
 #[test]
-fn set_target_is_directory() -> Result<(), Box<Error>> {
+fn set_target_is_directory() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", DIRECTORY])
+            .args(["set", DIRECTORY])
             .assert()
             .failure()
             .stderr(similar(
@@ -302,10 +514,10 @@ Error: Target is a directory: foo
 }
 
 #[test]
-fn set_secrets_dont_match() -> Result<(), Box<Error>> {
+fn set_secrets_dont_match() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", NON_EXISTING_SECRET])
+            .args(["set", NON_EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\nfoo\nother\n")
             .output()?
@@ -318,16 +530,16 @@ Inputs did not match.
 ",
             ));
 
-        assert_eq!(repo.path().join(NON_EXISTING_SECRET).exists(), false);
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
         Ok(())
     })
 }
 
 #[test]
-fn set_new_success() -> Result<(), Box<Error>> {
+fn set_new_success() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", NON_EXISTING_SECRET])
+            .args(["set", NON_EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\nfoo\nfoo\n")
             .output()?
@@ -338,20 +550,17 @@ fn set_new_success() -> Result<(), Box<Error>> {
             ));
 
         let secret_path = repo.path().join(NON_EXISTING_SECRET);
-        assert_eq!(secret_path.is_file(), true);
-        assert_eq!(secret_path.metadata()?.len() > 0, true);
+        assert!(secret_path.is_file());
+        assert!(secret_path.metadata()?.len() > 0);
         Ok(())
     })
 }
 
 #[test]
-fn set_new_cannot_create_parent_dirs() -> Result<(), Box<Error>> {
+fn set_new_cannot_create_parent_dirs() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", INVALID_SECRET_PATH_DEEP])
-            .with_stdin()
-            .buffer("qwerty\nfoo\nfoo\n")
-            .output()?
+            .args(["set", INVALID_SECRET_PATH_DEEP])
             .assert()
             .failure()
             .stderr(similar("Error: Cannot create directory: foo/@bar\n"));
@@ -361,10 +570,10 @@ fn set_new_cannot_create_parent_dirs() -> Result<(), Box<Error>> {
 }
 
 #[test]
-fn set_new_creates_parent_dirs() -> Result<(), Box<Error>> {
+fn set_new_creates_parent_dirs() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", NON_EXISTING_SECRET_DEEP])
+            .args(["set", NON_EXISTING_SECRET_DEEP])
             .with_stdin()
             .buffer("qwerty\nfoo\nfoo\n")
             .output()?
@@ -375,17 +584,17 @@ fn set_new_creates_parent_dirs() -> Result<(), Box<Error>> {
             ));
 
         let secret_path = repo.path().join(NON_EXISTING_SECRET_DEEP);
-        assert_eq!(secret_path.is_file(), true);
-        assert_eq!(secret_path.metadata()?.len() > 0, true);
+        assert!(secret_path.is_file());
+        assert!(secret_path.metadata()?.len() > 0);
         Ok(())
     })
 }
 
 #[test]
-fn set_replace_existing_success() -> Result<(), Box<Error>> {
+fn set_replace_existing_success() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, _, repo| {
         cmd.current_dir(repo.path())
-            .args(&["set", EXISTING_SECRET])
+            .args(["set", EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\nquux\nquux\n")
             .output()?
@@ -396,21 +605,21 @@ fn set_replace_existing_success() -> Result<(), Box<Error>> {
             ));
 
         let secret_path = repo.path().join(EXISTING_SECRET);
-        assert_eq!(secret_path.is_file(), true);
-        assert_eq!(secret_path.metadata()?.len() > 0, true);
+        assert!(secret_path.is_file());
+        assert!(secret_path.metadata()?.len() > 0);
         Ok(())
     })
 }
 
 #[test]
-fn set_suggestions() -> Result<(), Box<Error>> {
+fn set_suggestions() -> Result<(), Box<dyn Error>> {
     run_test(|cmd, home, repo| {
         write!(
             fs::File::create(home.join(".sala.toml"))?,
             "password-generator = \"sh -c 'echo foo; echo bar; echo baz'\"",
         )?;
         cmd.current_dir(repo.path())
-            .args(&["set", NON_EXISTING_SECRET])
+            .args(["set", NON_EXISTING_SECRET])
             .with_stdin()
             .buffer("qwerty\nquux\nquux\n")
             .output()?
@@ -427,109 +636,2996 @@ fn set_suggestions() -> Result<(), Box<Error>> {
             ));
 
         let secret_path = repo.path().join(EXISTING_SECRET);
-        assert_eq!(secret_path.is_file(), true);
-        assert_eq!(secret_path.metadata()?.len() > 0, true);
+        assert!(secret_path.is_file());
+        assert!(secret_path.metadata()?.len() > 0);
         Ok(())
     })
 }
 
+fn read_secret(home: &Path, repo: &TempRepo, path: &str) -> Result<String, Box<dyn Error>> {
+    let output = sala(home)?
+        .current_dir(repo.path())
+        .args(["-r", "get", path])
+        .with_stdin()
+        .buffer("qwerty\n")
+        .output()?;
+    Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+}
+
 #[test]
-fn implicit_set() -> Result<(), Box<Error>> {
-    run_test(|cmd, _, repo| {
+fn set_generate() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            home.join(".sala.toml"),
+            "password-length = 12\npassword-classes = [\"digits\", \"symbols\"]\n",
+        )?;
         cmd.current_dir(repo.path())
-            .arg(NON_EXISTING_SECRET)
+            .args(["set", "--generate", NON_EXISTING_SECRET])
             .with_stdin()
-            .buffer("qwerty\nfoo\nfoo\n")
+            .buffer("qwerty\n")
             .output()?
             .assert()
             .success()
-            .stderr(similar(
-                "Enter the master passphrase: Type a new secret for foo/@new: Confirm: ",
-            ));
-
-        let secret_path = repo.path().join(NON_EXISTING_SECRET);
-        assert_eq!(secret_path.is_file(), true);
-        assert_eq!(secret_path.metadata()?.len() > 0, true);
+            .stderr(similar("Enter the master passphrase: "));
+
+        let secret = read_secret(home, repo, NON_EXISTING_SECRET)?;
+        assert_eq!(secret.chars().count(), 12);
+        assert!(secret
+            .chars()
+            .all(|c| c.is_ascii_punctuation() || c.is_ascii_digit()));
+        assert!(secret.chars().any(|c| c.is_ascii_digit()));
+        assert!(secret.chars().any(|c| c.is_ascii_punctuation()));
         Ok(())
     })
 }
 
-// init
-
 #[test]
-fn init_already_initialized() -> Result<(), Box<Error>> {
-    run_test(|cmd, _, repo| {
+fn set_generate_words() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join("words"), "11111\tapple\n11112\tbanana\n")?;
+        fs::write(
+            home.join(".sala.toml"),
+            format!(
+                "password-style = \"words\"\n\
+                 password-wordlist = \"{}\"\n\
+                 password-words = 4\n\
+                 password-separator = \"-\"\n",
+                home.join("words").to_string_lossy()
+            ),
+        )?;
         cmd.current_dir(repo.path())
-            .arg("init")
+            .args(["set", "-g", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
             .output()?
             .assert()
-            .failure()
-            .stderr(similar("Error: The master key already exists\n"));
+            .success();
 
+        let secret = read_secret(home, repo, NON_EXISTING_SECRET)?;
+        let words: Vec<&str> = secret.split('-').collect();
+        assert_eq!(words.len(), 4);
+        assert!(words.iter().all(|w| *w == "apple" || *w == "banana"));
         Ok(())
     })
 }
 
 #[test]
-fn init_passphrases_dont_match() -> Result<(), Box<Error>> {
-    run_test(|cmd, dir, _| {
-        cmd.current_dir(dir)
-            .arg("init")
-            .with_stdin()
-            .buffer("foobar\nquux\n")
-            .output()?
+fn set_generate_no_wordlist() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "password-style = \"words\"")?;
+        cmd.current_dir(repo.path())
+            .args(["set", "--generate", NON_EXISTING_SECRET])
             .assert()
             .failure()
             .stderr(similar(
-                "\
-Enter a master passphrase: Confirm: 
-Inputs did not match.
-",
+                "Error: Generating a password failed: password-wordlist is not set\n",
             ));
 
-        assert_eq!(dir.join(".sala/key").exists(), false);
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
         Ok(())
     })
 }
 
 #[test]
-fn init_success() -> Result<(), Box<Error>> {
-    run_test(|cmd, dir, _| {
-        cmd.current_dir(dir)
-            .arg("init")
-            .with_stdin()
-            .buffer("foobar\nfoobar\n")
-            .output()?
+fn set_generate_empty_generator() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "password-generator = \"  \"")?;
+        cmd.current_dir(repo.path())
+            .args(["set", "--generate", NON_EXISTING_SECRET])
             .assert()
-            .success()
-            .stdout(similar(
-                "\
-Please pick a master passphrase. It is used to encrypt a very long
-random key, which in turn is used to encrypt all the private data in
-this directory.
+            .code(1)
+            .stderr(similar(
+                "Error: Generating a password failed: Invalid command:   \n",
+            ));
 
-Make sure you remember the master passphrase and that it's strong
-enough for your privacy needs.
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
+        Ok(())
+    })
+}
 
+#[test]
+fn set_builtin_suggestions() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            home.join(".sala.toml"),
+            "password-suggestions = 3\npassword-length = 8\npassword-exclude-ambiguous = true\n",
+        )?;
+        let output = cmd
+            .current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n1\n")
+            .output()?;
+        let stdout = String::from_utf8(output.stdout.clone())?;
+        output.assert().success();
+        let suggestions: Vec<&str> = stdout
+            .lines()
+            .filter_map(|line| line.split_once(". "))
+            .map(|(_, suggestion)| suggestion)
+            .collect();
+        assert_eq!(suggestions.len(), 3);
+        assert!(suggestions
+            .iter()
+            .all(|s| s.len() == 8 && !s.contains(|c| "0O1Il".contains(c))));
+        assert_eq!(
+            read_secret(home, repo, NON_EXISTING_SECRET)?,
+            suggestions[1]
+        );
+        Ok(())
+    })
+}
 
-Generating a master key (512 bits)... done
-",
-            ))
-            .stderr(similar("Enter a master passphrase: Confirm: "));
+#[test]
+fn set_fields() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .args(["set", "--fields", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nhunter2\nhunter2\nusername\nalice\nnotes\nfirst\\\nsecond\n\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar(
+                "Enter the master passphrase: Type a new secret for foo/@new: Confirm: \
+                 Field name (empty to finish): Value for username: \
+                 Field name (empty to finish): Value for notes: > \
+                 Field name (empty to finish): ",
+            ));
 
-        assert_eq!(dir.join(".sala/key").metadata()?.len() > 0, true);
+        assert_eq!(
+            read_secret(home, repo, NON_EXISTING_SECRET)?,
+            "hunter2\nusername: alice\nnotes: first\n  second"
+        );
         Ok(())
     })
 }
 
-// misc
-
 #[test]
-fn no_args() -> Result<(), Box<Error>> {
-    run_test(|cmd, dir, _| {
-        cmd.current_dir(dir)
-            .assert()
-            .failure()
+fn set_invalid_field_name() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["set", "-f", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nhunter2\nhunter2\nuser name\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Enter the master passphrase: Type a new secret for foo/@new: Confirm: \
+                 Field name (empty to finish): \nError: Invalid field name: user name\n",
+            ));
+
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn implicit_set() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .arg(NON_EXISTING_SECRET)
+            .with_stdin()
+            .buffer("qwerty\nfoo\nfoo\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar(
+                "Enter the master passphrase: Type a new secret for foo/@new: Confirm: ",
+            ));
+
+        let secret_path = repo.path().join(NON_EXISTING_SECRET);
+        assert!(secret_path.is_file());
+        assert!(secret_path.metadata()?.len() > 0);
+        Ok(())
+    })
+}
+
+// edit
+
+#[test]
+fn edit_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let editor = format!(
+            "sh -c 'stat -c %a \"$0\" > {0}/mode; dirname \"$0\" > {0}/dir; \
+             touch \"$0~\"; sed -i s/baz/quux/ \"$0\"'",
+            home.to_string_lossy()
+        );
+        cmd.current_dir(repo.path())
+            .env_remove("VISUAL")
+            .env("EDITOR", editor)
+            .args(["edit", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(""));
+
+        assert_eq!(read_secret(home, repo, EXISTING_SECRET)?, "quux");
+        assert_eq!(fs::read_to_string(home.join("mode"))?, "600\n");
+        let dir = fs::read_to_string(home.join("dir"))?;
+        assert!(!Path::new(dir.trim_end()).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn edit_unchanged() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        let secret_path = repo.path().join(EXISTING_SECRET);
+        let before = fs::read(&secret_path)?;
+        cmd.current_dir(repo.path())
+            .env_remove("VISUAL")
+            .env("EDITOR", "true")
+            .args(["edit", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                "
+No changes to foo/@bar
+
+",
+            ));
+
+        assert_eq!(fs::read(&secret_path)?, before);
+        Ok(())
+    })
+}
+
+#[test]
+fn edit_editor_fails() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .env_remove("VISUAL")
+            .env("EDITOR", "sh -c 'echo changed > \"$0\"; exit 1'")
+            .args(["edit", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Enter the master passphrase: \nError: Editing failed: Editor exited with exit status: 1\n",
+            ));
+
+        assert_eq!(read_secret(home, repo, EXISTING_SECRET)?, "baz");
+        Ok(())
+    })
+}
+
+#[test]
+fn edit_new_secret() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .env_remove("VISUAL")
+            .env("EDITOR", "sh -c 'printf \"new\\nsecret\\n\" > \"$0\"'")
+            .args(["edit", NON_EXISTING_SECRET_DEEP])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert_eq!(
+            read_secret(home, repo, NON_EXISTING_SECRET_DEEP)?,
+            "new\nsecret"
+        );
+        Ok(())
+    })
+}
+
+// init
+
+#[test]
+fn init_already_initialized() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .arg("init")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar("Error: The master key already exists\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn init_passphrases_dont_match() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .arg("init")
+            .with_stdin()
+            .buffer("foobar\nquux\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\
+Enter a master passphrase: Confirm: 
+Inputs did not match.
+",
+            ));
+
+        assert!(!dir.join(".sala/key").exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn init_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .arg("init")
+            .with_stdin()
+            .buffer("foobar\nfoobar\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+Please pick a master passphrase. It is used to encrypt a very long
+random key, which in turn is used to encrypt all the private data in
+this directory.
+
+Make sure you remember the master passphrase and that it's strong
+enough for your privacy needs.
+
+
+Generating a master key (512 bits)... done
+",
+            ))
+            .stderr(similar("Enter a master passphrase: Confirm: "));
+
+        assert!(dir.join(".sala/key").metadata()?.len() > 0);
+        Ok(())
+    })
+}
+
+// mv, cp, rm
+
+fn add_hook(repo: &TempRepo, name: &str) -> Result<(), Box<dyn Error>> {
+    let hook_path = repo.path().join(".sala").join(name);
+    fs::write(
+        &hook_path,
+        format!("#!/bin/sh\necho {} \"$@\" >> ../hooks.log\n", name),
+    )?;
+    fs::set_permissions(&hook_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn mv_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "post-move")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["mv", EXISTING_SECRET, "new/dir/@bar"])
+            .assert()
+            .success()
+            .stdout(similar(""));
+
+        assert!(!repo.path().join(DIRECTORY).exists());
+        assert_eq!(read_secret(home, repo, "new/dir/@bar")?, "baz");
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-move foo/@bar new/dir/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn mv_into_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        fs::create_dir(repo.path().join("other"))?;
+        cmd.current_dir(repo.path())
+            .args(["mv", DIRECTORY, "other"])
+            .assert()
+            .success();
+
+        assert!(repo.path().join("other/foo/@bar").is_file());
+        Ok(())
+    })
+}
+
+#[test]
+fn mv_into_new_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .args(["mv", EXISTING_SECRET, "new/dir/"])
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, "new/dir/@bar")?, "baz");
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["mv", "new/dir/@bar", "."])
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, "@bar")?, "baz");
+        assert!(!repo.path().join("new").exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn mv_target_exists() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::copy(
+            repo.path().join(EXISTING_SECRET),
+            repo.path().join(NON_EXISTING_SECRET),
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["mv", NON_EXISTING_SECRET, EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: Target already exists: foo/@bar\nUse --force to replace it\n",
+            ));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["mv", "--force", NON_EXISTING_SECRET, EXISTING_SECRET])
+            .assert()
+            .success();
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn mv_invalid_path() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["mv", EXISTING_SECRET, ".sala/key"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: Not a valid secret path: .sala/key\n"));
+
+        assert!(repo.path().join(EXISTING_SECRET).is_file());
+        Ok(())
+    })
+}
+
+#[test]
+fn set_invalid_path() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["set", "../@outside"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: Not a valid secret path: ../@outside\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn cp_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "post-set")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["cp", DIRECTORY, "copy"])
+            .assert()
+            .success();
+
+        assert!(repo.path().join(EXISTING_SECRET).is_file());
+        assert_eq!(read_secret(home, repo, "copy/@bar")?, "baz");
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-set copy/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn cp_into_new_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .args(["cp", EXISTING_SECRET, "new/"])
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, "new/@bar")?, "baz");
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["cp", EXISTING_SECRET, "./"])
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, "@bar")?, "baz");
+        assert!(repo.path().join(EXISTING_SECRET).is_file());
+        Ok(())
+    })
+}
+
+#[test]
+fn rm_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "post-remove")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["rm", EXISTING_SECRET])
+            .assert()
+            .success()
+            .stdout(similar(""));
+
+        assert!(!repo.path().join(DIRECTORY).exists());
+        assert!(repo.path().join(".sala/key").is_file());
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-remove foo/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn rm_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .args(["rm", DIRECTORY])
+            .assert()
+            .failure()
+            .stderr(similar("Error: Target is a directory: foo\n"));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rm", "--recursive", DIRECTORY])
+            .assert()
+            .success();
+        assert!(!repo.path().join(DIRECTORY).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn rm_does_not_exist() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["rm", NON_EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(similar("Error: File does not exist or invalid: foo/@new\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn rm_trash() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .args(["rm", "--trash", EXISTING_SECRET])
+            .assert()
+            .success();
+        assert!(!repo.path().join(DIRECTORY).exists());
+
+        let trash = fs::read_dir(repo.path().join(".sala/trash"))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<PathBuf>, io::Error>>()?;
+        assert_eq!(trash.len(), 1);
+        let trashed = trash[0].join(EXISTING_SECRET);
+        assert!(trashed.is_file());
+
+        // The trash is re-encrypted along with the other secrets
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("rekey")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, &trashed.to_string_lossy())?, "baz");
+        Ok(())
+    })
+}
+
+// ls
+
+fn add_listing_files(repo: &TempRepo) -> Result<(), Box<dyn Error>> {
+    for path in [
+        "example-service.com/+webmail/@myuser",
+        "example-service.com/+webmail/@otheruser",
+        "example-service.com/+adminpanel/@admin",
+        "my-linux-box/@root",
+        "notes",
+        "foo/@bar.tmp",
+        ".git/config",
+    ] {
+        let path = repo.path().join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, "")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn ls_no_repo() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .arg("ls")
+            .assert()
+            .failure()
+            .stderr(similar("No repository. Run `sala init' first\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ls_tree() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+example-service.com
+|-- +adminpanel: @admin
+`-- +webmail: @myuser, @otheruser
+foo: @bar
+my-linux-box: @root
+notes
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ls_raw() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "ls"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+example-service.com/+adminpanel/@admin
+example-service.com/+webmail/@myuser
+example-service.com/+webmail/@otheruser
+foo/@bar
+my-linux-box/@root
+notes
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ls_subdirectory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["tree", "example-service.com"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
++adminpanel: @admin
++webmail: @myuser, @otheruser
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ls_hidden_directory() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["ls", ".sala"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: File does not exist or invalid: .sala\n"));
+
+        Ok(())
+    })
+}
+
+// find
+
+#[test]
+fn find_glob() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "find", "*/+webmail/*"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+example-service.com/+webmail/@myuser
+example-service.com/+webmail/@otheruser
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn find_glob_tree() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["find", "*@my*"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+example-service.com
+`-- +webmail: @myuser
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn find_regex_ignore_case() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "find", "-e", "-i", "ROOT|admin$"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "\
+example-service.com/+adminpanel/@admin
+my-linux-box/@root
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn find_case_sensitive() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        add_listing_files(repo)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "find", "*ROOT"])
+            .assert()
+            .success()
+            .stdout(similar(""));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn find_invalid_pattern() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["find", "-e", "foo("])
+            .assert()
+            .failure()
+            .stderr(predicate::str::starts_with("Error: Invalid pattern: "));
+
+        Ok(())
+    })
+}
+
+// grep
+
+#[test]
+fn grep_no_repo() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .args(["grep", "foo"])
+            .assert()
+            .failure()
+            .stderr(similar("No repository. Run `sala init' first\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn grep_paths() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nquux\nquux\n")
+            .output()?
+            .assert()
+            .success();
+
+        cmd.current_dir(repo.path())
+            .args(["grep", "^(baz|quux)$"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar("Enter the master passphrase: "))
+            .stdout(similar("foo/@bar\nfoo/@new\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn grep_no_match() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["grep", "BAZ"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(""));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn grep_lines_ignore_case() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["grep", "-i", "--lines", "BAZ"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("foo/@bar: baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn grep_wrong_passphrase() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["grep", "baz"])
+            .with_stdin()
+            .buffer("this is wrong\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\
+Enter the master passphrase: 
+Error: Unable to unlock the encryption key
+",
+            ));
+
+        Ok(())
+    })
+}
+
+// passwd
+
+#[test]
+fn passwd_no_repo() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .arg("passwd")
+            .assert()
+            .failure()
+            .stderr(similar("No repository. Run `sala init' first\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn passwd_wrong_passphrase() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        let key = fs::read(repo.path().join(".sala/key"))?;
+        cmd.current_dir(repo.path())
+            .arg("passwd")
+            .with_stdin()
+            .buffer("this is wrong\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\
+Enter the master passphrase: 
+Error: Unable to unlock the encryption key
+",
+            ));
+
+        assert_eq!(fs::read(repo.path().join(".sala/key"))?, key);
+        Ok(())
+    })
+}
+
+#[test]
+fn passwd_passphrases_dont_match() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        let key = fs::read(repo.path().join(".sala/key"))?;
+        cmd.current_dir(repo.path())
+            .arg("passwd")
+            .with_stdin()
+            .buffer("qwerty\nfoo\nbar\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\
+Enter the master passphrase: Enter a new master passphrase: Confirm: 
+Inputs did not match.
+",
+            ));
+
+        assert_eq!(fs::read(repo.path().join(".sala/key"))?, key);
+        Ok(())
+    })
+}
+
+#[test]
+fn passwd_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .arg("passwd")
+            .with_stdin()
+            .buffer("qwerty\nfoo\nfoo\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar(
+                "Enter the master passphrase: Enter a new master passphrase: Confirm: ",
+            ));
+
+        assert!(!repo.path().join(".sala/key.tmp").exists());
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("foo\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+// rekey
+
+#[test]
+fn rekey_no_repo() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .arg("rekey")
+            .assert()
+            .failure()
+            .stderr(similar("No repository. Run `sala init' first\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn rekey_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let key = fs::read(repo.path().join(".sala/key"))?;
+        let secret = fs::read(repo.path().join(EXISTING_SECRET))?;
+        cmd.current_dir(repo.path())
+            .arg("rekey")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                "
+Generating a new master key (512 bits)... done
+Re-encrypting 1 secrets... done
+",
+            ))
+            .stderr(similar("Enter the master passphrase: "));
+
+        assert_ne!(fs::read(repo.path().join(".sala/key"))?, key);
+        assert_ne!(fs::read(repo.path().join(EXISTING_SECRET))?, secret);
+        assert!(!repo.path().join(".sala/rekey").exists());
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn rekey_not_in_progress() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["rekey", "--continue"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: No master key rotation in progress\n"));
+
+        Ok(())
+    })
+}
+
+fn interrupted_rekey(home: &Path, repo: &TempRepo) -> Result<(), Box<dyn Error>> {
+    let corrupt = repo.path().join("foo/@corrupt");
+    fs::write(&corrupt, "this is not encrypted")?;
+    sala(home)?
+        .current_dir(repo.path())
+        .arg("rekey")
+        .with_stdin()
+        .buffer("qwerty\n")
+        .output()?
+        .assert()
+        .failure()
+        .stderr(similar(
+            "\
+Enter the master passphrase: 
+Error: Unable to decrypt foo/@corrupt: invalid OpenPGP message: bad packet header
+",
+        ));
+    assert!(repo.path().join(".sala/rekey").is_dir());
+
+    sala(home)?
+        .current_dir(repo.path())
+        .args(["get", EXISTING_SECRET])
+        .assert()
+        .failure()
+        .stderr(similar(
+            "\
+Error: A master key rotation is in progress
+Run `sala rekey --continue' or `sala rekey --abort' first
+",
+        ));
+
+    fs::remove_file(&corrupt)?;
+    Ok(())
+}
+
+#[test]
+fn rekey_continue() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let key = fs::read(repo.path().join(".sala/key"))?;
+        interrupted_rekey(home, repo)?;
+
+        cmd.current_dir(repo.path())
+            .args(["rekey", "--continue"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("Re-encrypting 1 secrets... done\n"));
+
+        assert_ne!(fs::read(repo.path().join(".sala/key"))?, key);
+        assert!(!repo.path().join(".sala/rekey").exists());
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn rekey_abort() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let key = fs::read(repo.path().join(".sala/key"))?;
+        interrupted_rekey(home, repo)?;
+
+        cmd.current_dir(repo.path())
+            .args(["rekey", "--abort"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("Restoring 1 secrets... done\n"));
+
+        assert_eq!(fs::read(repo.path().join(".sala/key"))?, key);
+        assert!(!repo.path().join(".sala/rekey").exists());
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn rekey_abort_after_key_replaced() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .arg("rekey")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .assert()
+            .success();
+        let key = fs::read(repo.path().join(".sala/key"))?;
+
+        // Interrupted after .sala/key was replaced, but before the cleanup
+        fs::create_dir(repo.path().join(".sala/rekey"))?;
+        fs::write(repo.path().join(".sala/rekey/journal"), "foo/@bar\n")?;
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rekey", "--abort"])
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "\
+Error: The new master key is already in use, the rotation can't be aborted
+Run `sala rekey --continue' to finish it
+",
+            ));
+        assert!(repo.path().join(".sala/rekey").exists());
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rekey", "--continue"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .assert()
+            .success();
+        assert_eq!(fs::read(repo.path().join(".sala/key"))?, key);
+        assert!(!repo.path().join(".sala/rekey").exists());
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+// agent
+
+struct Agent(Child);
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_agent(home: &Path) -> Result<Agent, Box<dyn Error>> {
+    let agent = Agent(
+        sala(home)?
+            .args(["agent", "--foreground"])
+            .stdout(Stdio::null())
+            .spawn()?,
+    );
+    for _ in 0..100 {
+        if home.join("agent/agent.sock").exists() {
+            return Ok(agent);
+        }
+        sleep(Duration::from_millis(50));
+    }
+    Err("agent did not start".into())
+}
+
+fn get_without_passphrase(home: &Path, repo: &TempRepo) -> Result<Output, Box<dyn Error>> {
+    Ok(sala(home)?
+        .current_dir(repo.path())
+        .args(["-r", "get", EXISTING_SECRET])
+        .with_stdin()
+        .buffer("")
+        .output()?)
+}
+
+#[test]
+fn lock_no_agent() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, _| {
+        cmd.arg("lock")
+            .assert()
+            .failure()
+            .stderr(similar("Error: The agent is not running\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn agent_already_running() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, _| {
+        let _agent = start_agent(home)?;
+        cmd.args(["agent", "--foreground"])
+            .assert()
+            .failure()
+            .stderr(similar("Error: The agent is already running\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn agent_socket_permissions() -> Result<(), Box<dyn Error>> {
+    run_test(|_, home, _| {
+        let _agent = start_agent(home)?;
+        let mode = |path: &Path| -> Result<u32, Box<dyn Error>> {
+            Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+        assert_eq!(mode(&home.join("agent"))?, 0o700);
+        assert_eq!(mode(&home.join("agent/agent.sock"))?, 0o600);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn agent_caches_key() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let _agent = start_agent(home)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar("Enter the master passphrase: "))
+            .stdout(similar("baz\n"));
+
+        get_without_passphrase(home, repo)?
+            .assert()
+            .success()
+            .stderr(similar(""))
+            .stdout(similar("baz\n"));
+
+        sala(home)?.arg("lock").assert().success();
+        get_without_passphrase(home, repo)?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\
+Enter the master passphrase: 
+Error: Unable to unlock the encryption key
+",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn agent_idle_timeout() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "agent-idle-timeout = 1")?;
+        let _agent = start_agent(home)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        sleep(Duration::from_secs(2));
+        get_without_passphrase(home, repo)?.assert().failure();
+
+        Ok(())
+    })
+}
+
+#[test]
+fn agent_key_changed() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let _agent = start_agent(home)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("rekey")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        get_without_passphrase(home, repo)?.assert().failure();
+
+        Ok(())
+    })
+}
+
+// clipboard
+
+fn clipboard_config(home: &Path, clear_after: u64) -> Result<PathBuf, Box<dyn Error>> {
+    let clipboard = home.join("clipboard");
+    fs::write(&clipboard, "previous")?;
+    fs::write(
+        home.join(".sala.toml"),
+        format!(
+            "clipboard-copy = \"sh -c 'cat > {0}'\"\n\
+             clipboard-paste = \"cat {0}\"\n\
+             clipboard-clear-after = {1}\n",
+            clipboard.to_string_lossy(),
+            clear_after
+        ),
+    )?;
+    Ok(clipboard)
+}
+
+#[test]
+fn get_clip_no_clipboard() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["get", "--clip", EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: No clipboard command configured, set clipboard-copy\n",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_clip_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let clipboard = clipboard_config(home, 0)?;
+        cmd.current_dir(repo.path())
+            .args(["get", "--clip", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(similar("Enter the master passphrase: "))
+            .stdout(similar(
+                "
+Copied foo/@bar to the clipboard
+
+",
+            ));
+        assert_eq!(fs::read_to_string(&clipboard)?, "baz");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_clip_restores_clipboard() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let clipboard = clipboard_config(home, 1)?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", "-c", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(""));
+        assert_eq!(fs::read_to_string(&clipboard)?, "baz");
+
+        sleep(Duration::from_secs(3));
+        assert_eq!(fs::read_to_string(&clipboard)?, "previous");
+
+        Ok(())
+    })
+}
+
+// backends
+
+#[test]
+fn get_gpg_backend() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "backend = \"gpg\"")?;
+        cmd.current_dir(repo.path())
+            .args(["-r", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn set_native_get_gpg() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "cipher = \"AES256\"")?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nfoo\nfoo\n")
+            .output()?
+            .assert()
+            .success();
+
+        fs::write(home.join(".sala.toml"), "backend = \"gpg\"")?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("foo\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn set_gpg_get_native() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            home.join(".sala.toml"),
+            "backend = \"gpg\"\ncipher = \"CAMELLIA192\"",
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nfoo\nfoo\n")
+            .output()?
+            .assert()
+            .success();
+
+        fs::remove_file(home.join(".sala.toml"))?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("foo\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn gpg_backend_large_secret() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "backend = \"gpg\"")?;
+        // gpg compresses its input, so the secret can't be too repetitive
+        let mut state: u32 = 1;
+        let secret: String = (0..400_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'a' + (state >> 16) as u8 % 26)
+            })
+            .collect();
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["set", "--stdin", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer(secret.as_str())
+            .assert()
+            .success();
+
+        let output = sala(home)?
+            .current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", home.join("passphrase"))
+            .args(["-r", "get", NON_EXISTING_SECRET])
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, format!("{}\n", secret).as_bytes());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn gpg_backend_not_found() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "backend = \"gpg\"")?;
+        cmd.current_dir(repo.path())
+            .env("PATH", "")
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["get", EXISTING_SECRET])
+            .assert()
+            .code(6)
+            .stderr(similar(
+                "\nError: The encryption backend failed: \
+                 cannot run gpg: No such file or directory (os error 2)\n",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn native_backend_unsupported_message() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        // Messages that the native backend can't read are decrypted with
+        // the gpg binary
+        for name in &["@aead", "@blowfish"] {
+            fs::copy(
+                Path::new("tests/fixtures").join(name),
+                repo.path().join("foo").join(name),
+            )?;
+        }
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["-r", "get", "foo/@aead"])
+            .assert()
+            .success()
+            .stdout(similar("aead\n"));
+        sala(home)?
+            .current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["-r", "get", "foo/@blowfish"])
+            .assert()
+            .success()
+            .stdout(similar("blowfish\n"));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .env("PATH", "")
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["get", "foo/@aead"])
+            .assert()
+            .code(6);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn native_backend_decompression_limit() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        // 65 MiB of zeros, compressed by gpg
+        fs::copy("tests/fixtures/@large", repo.path().join("foo/@large"))?;
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["get", "foo/@large"])
+            .assert()
+            .code(6)
+            .stderr(predicate::str::contains(
+                "Unable to decrypt foo/@large: \
+                 invalid OpenPGP message: bad or too large compressed data",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn set_invalid_backend() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "backend = \"openssl\"")?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .assert()
+            .failure();
+
+        Ok(())
+    })
+}
+
+// non-interactive input
+
+fn write_passphrase_file(home: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let path = home.join("passphrase");
+    fs::write(&path, "qwerty\n")?;
+    Ok(path)
+}
+
+#[test]
+fn get_passphrase_file() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["-r", "get", EXISTING_SECRET])
+            .assert()
+            .success()
+            .stderr(similar(""))
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_passphrase_fd() -> Result<(), Box<dyn Error>> {
+    run_test(|_, home, repo| {
+        Command::new("sh")
+            .current_dir(repo.path())
+            .env("HOME", home)
+            .env("SALA_AGENT_SOCK", home.join("agent/agent.sock"))
+            .args([
+                "-c",
+                "exec \"$0\" --passphrase-fd 3 -r get foo/@bar 3<\"$1\"",
+            ])
+            .arg(cargo_bin("sala"))
+            .arg(write_passphrase_file(home)?)
+            .assert()
+            .success()
+            .stderr(similar(""))
+            .stdout(similar("baz\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_passphrase_fd_invalid() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["--passphrase-fd", "9", "get", EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "\nError: Reading input failed: Bad file descriptor (os error 9)\n",
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn set_stdin() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let secret: &[u8] = b"first line\nsecond line\n\x00\xff";
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["set", "--stdin", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer(secret)
+            .assert()
+            .success()
+            .stderr(similar(""));
+
+        let output = sala(home)?
+            .current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", home.join("passphrase"))
+            .args(["-r", "get", NON_EXISTING_SECRET])
+            .output()?;
+        assert!(output.status.success());
+        assert_eq!(output.stdout, [secret, b"\n"].concat());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn init_passphrase_file() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, _| {
+        let repo = tempdir()?;
+        cmd.current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .arg("init")
+            .assert()
+            .success();
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--stdin", "@foo"])
+            .with_stdin()
+            .buffer("qwerty\nbar")
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["-r", "get", "@foo"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .assert()
+            .success()
+            .stdout(similar("bar\n"));
+
+        Ok(())
+    })
+}
+
+// hooks
+
+fn add_hook_script(repo: &TempRepo, name: &str, script: &str) -> Result<(), Box<dyn Error>> {
+    let hook_path = repo.path().join(".sala").join(name);
+    fs::write(&hook_path, format!("#!/bin/sh\n{}", script))?;
+    fs::set_permissions(&hook_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+fn trust_hooks(home: &Path, repo: &TempRepo) -> Result<(), Box<dyn Error>> {
+    sala(home)?
+        .current_dir(repo.path())
+        .args(["trust", "--yes"])
+        .assert()
+        .success();
+    Ok(())
+}
+
+fn add_failing_hook(repo: &TempRepo, name: &str) -> Result<(), Box<dyn Error>> {
+    add_hook_script(repo, name, "exit 3\n")
+}
+
+#[test]
+fn pre_hooks() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "pre-get")?;
+        add_hook(repo, "pre-unlock")?;
+        add_hook(repo, "post-get")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "pre-get foo/@bar\npre-unlock\npost-get foo/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn get_hooks_other_commands() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "pre-get")?;
+        add_hook(repo, "post-get")?;
+        add_hook(repo, "pre-set")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["grep", "baz"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .env_remove("VISUAL")
+            .env("EDITOR", "true")
+            .args(["edit", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        // fsck doesn't show the secrets
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("fsck")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "pre-get\npost-get foo/@bar\n\
+             pre-get foo/@bar\npre-set foo/@bar\npost-get foo/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn pre_get_hook_once() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::copy(
+            repo.path().join(EXISTING_SECRET),
+            repo.path().join(NON_EXISTING_SECRET),
+        )?;
+        add_hook(repo, "pre-get")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["grep", "baz"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["get", DIRECTORY])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["get", EXISTING_SECRET, NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        // Once per command, with the path only for a single secret
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "pre-get\npre-get\npre-get\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn pre_hook_aborts() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_failing_hook(repo, "pre-set")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["set", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nnew\nnew\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: Hook \"./.sala/pre-set\" failed with status 3, aborting\n",
+            ));
+        assert_eq!(read_secret(home, repo, EXISTING_SECRET)?, "baz");
+
+        add_failing_hook(repo, "pre-remove")?;
+        trust_hooks(home, repo)?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rm", EXISTING_SECRET])
+            .assert()
+            .failure();
+        assert!(repo.path().join(EXISTING_SECRET).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn hook_input() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook_script(
+            repo,
+            "post-get",
+            "cat > ../input.json\necho \"$SALA_EVENT $SALA_PATH $SALA_REPO\" > ../env.log\n",
+        )?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+
+        let repo_root = repo.path().canonicalize()?;
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../input.json"))?,
+            format!(
+                r#"{{"event":"post-get","path":"foo/@bar","repo":"{}","secret":"baz"}}"#,
+                repo_root.display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../env.log"))?,
+            format!("post-get foo/@bar {}\n", repo_root.display())
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn hook_stderr_and_timeout() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook_script(repo, "pre-set", "echo not allowed >&2\nexit 3\n")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["set", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nnew\nnew\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: Hook \"./.sala/pre-set\" failed with status 3, aborting\n  not allowed\n",
+            ));
+
+        fs::remove_file(repo.path().join(".sala/pre-set"))?;
+        fs::write(repo.path().join(".sala/config"), "hook-timeout = 1\n")?;
+        add_hook_script(repo, "post-remove", "sleep 5\n")?;
+        trust_hooks(home, repo)?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rm", EXISTING_SECRET])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Hook \"./.sala/post-remove\" timed out after 1 seconds",
+            ));
+        Ok(())
+    })
+}
+
+fn add_hooks_d_script(
+    repo: &TempRepo,
+    event: &str,
+    name: &str,
+    script: &str,
+) -> Result<(), Box<dyn Error>> {
+    let dir = repo.path().join(".sala/hooks.d").join(event);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(name), format!("#!/bin/sh\n{}", script))?;
+    fs::set_permissions(dir.join(name), fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn hooks_d() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "post-set")?;
+        add_hooks_d_script(
+            repo,
+            "post-set",
+            "20-second",
+            "echo second \"$SALA_PATH\" >> ../hooks.log\n",
+        )?;
+        add_hooks_d_script(
+            repo,
+            "post-set",
+            "10-first",
+            "echo first \"$SALA_PATH\" >> ../hooks.log\n",
+        )?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nnew\nnew\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-set foo/@new\nfirst foo/@new\nsecond foo/@new\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn hook_policy() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "[hook-policy]\n\"pre-set/10-check\" = \"warn\"\npost-remove = \"abort\"\n",
+        )?;
+        add_hooks_d_script(repo, "pre-set", "10-check", "exit 3\n")?;
+        trust_hooks(home, repo)?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nnew\nnew\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Hooks produced warnings:\n\
+                 Hook \"./.sala/hooks.d/pre-set/10-check\" failed with status 3",
+            ));
+        assert_eq!(read_secret(home, repo, NON_EXISTING_SECRET)?, "new");
+
+        sala(home)?
+            .current_dir(repo.path())
+            .env("SALA_PASSPHRASE_FILE", write_passphrase_file(home)?)
+            .args(["--format", "json", "set", "--stdin", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("newer")
+            .output()?
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                r#"{"hook_warnings":["Hook \"./.sala/hooks.d/pre-set/10-check\" failed with status 3"#,
+            ));
+
+        add_failing_hook(repo, "post-remove")?;
+        trust_hooks(home, repo)?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rm", NON_EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(similar(
+                "Error: Hook \"./.sala/post-remove\" failed with status 3, aborting\n",
+            ));
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
+        Ok(())
+    })
+}
+
+#[test]
+fn hook_policy_user_hooks() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let hook_dir = home.join(".config/sala");
+        fs::create_dir_all(&hook_dir)?;
+        fs::write(hook_dir.join("pre-set"), "#!/bin/sh\nexit 3\n")?;
+        fs::set_permissions(hook_dir.join("pre-set"), fs::Permissions::from_mode(0o755))?;
+        fs::write(
+            repo.path().join(".sala/config"),
+            "[hook-policy]\npre-set = \"warn\"\n\n\
+             [rules.\"foo/*\".hook-policy]\npre-set = \"warn\"\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .args(["set", NON_EXISTING_SECRET])
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains("failed with status 3, aborting"));
+        assert!(!repo.path().join(NON_EXISTING_SECRET).exists());
+
+        fs::write(
+            home.join(".config/sala.toml"),
+            "[hook-policy]\npre-set = \"warn\"\n",
+        )?;
+        sala(home)?
+            .current_dir(repo.path())
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nnew\nnew\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("failed with status 3"));
+        assert_eq!(read_secret(home, repo, NON_EXISTING_SECRET)?, "new");
+        Ok(())
+    })
+}
+
+#[test]
+fn untrusted_hooks() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        add_hook(repo, "post-get")?;
+        cmd.current_dir(repo.path())
+            .args(["get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Hook \"./.sala/post-get\" is not trusted, run `sala trust' to review it",
+            ));
+        assert!(!repo.path().join("../hooks.log").exists());
+
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("trust")
+            .with_stdin()
+            .buffer("y\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("Hook .sala/post-get is not trusted\n"))
+            .stderr(similar("Trust it? [y/N] "));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-get foo/@bar\n"
+        );
+
+        add_hook_script(repo, "post-get", "echo changed >> ../hooks.log\n")?;
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("trust")
+            .with_stdin()
+            .buffer("n\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                "Hook .sala/post-get has changed since it was trusted\n",
+            ));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "Hook \"./.sala/post-get\" has changed since it was trusted",
+            ));
+        assert_eq!(
+            fs::read_to_string(repo.path().join("../hooks.log"))?,
+            "post-get foo/@bar\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn untrusted_password_generator() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "password-generator = \"echo generated\"\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["set", "--generate", NON_EXISTING_SECRET])
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "Error: password-generator in .sala/config is not trusted, run `sala trust' to review it\n",
+            ));
+
+        trust_hooks(home, repo)?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--generate", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, NON_EXISTING_SECRET)?, "generated");
+        Ok(())
+    })
+}
+
+#[test]
+fn untrusted_rule_password_generator() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let config = "password-generator = \"echo top\"\n\
+                      \n\
+                      [rules.\"legacy/*\"]\n\
+                      password-generator = \"echo legacy\"\n";
+        fs::write(repo.path().join(".sala/config"), config)?;
+        cmd.current_dir(repo.path())
+            .args(["set", "--generate", "legacy/@x"])
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "Error: rules.\"legacy/*\".password-generator in .sala/config is not trusted, \
+                 run `sala trust' to review it\n",
+            ));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["trust", "--yes"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "password-generator = \"echo top\" in .sala/config is not trusted\n\
+                 rules.\"legacy/*\".password-generator = \"echo legacy\" in .sala/config \
+                 is not trusted\n",
+            ));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--generate", "legacy/@x"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, "legacy/@x")?, "legacy");
+
+        // Each generator is trusted separately
+        fs::write(
+            repo.path().join(".sala/config"),
+            config.replace("echo legacy", "echo changed"),
+        )?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--generate", "legacy/@y"])
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "Error: rules.\"legacy/*\".password-generator in .sala/config \
+                 has changed since it was trusted, run `sala trust' to review it\n",
+            ));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--generate", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(read_secret(home, repo, NON_EXISTING_SECRET)?, "top");
+        Ok(())
+    })
+}
+
+#[test]
+fn untrusted_clipboard_commands() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        let clipboard = clipboard_config(home, 0)?;
+        fs::write(
+            repo.path().join(".sala/config"),
+            format!(
+                "clipboard-copy = \"sh -c 'cat > {0}.repo'\"\n",
+                clipboard.to_string_lossy()
+            ),
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["get", "--clip", EXISTING_SECRET])
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "Error: clipboard-copy in .sala/config is not trusted, run `sala trust' to review it\n",
+            ));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["trust", "--yes"])
+            .assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                "clipboard-copy = \"sh -c 'cat > ",
+            ));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["get", "--clip", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(fs::read_to_string(home.join("clipboard.repo"))?, "baz");
+        assert_eq!(fs::read_to_string(&clipboard)?, "previous");
+        Ok(())
+    })
+}
+
+#[test]
+fn post_init_hook() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, _| {
+        let hook_dir = home.join(".config/sala");
+        fs::create_dir_all(&hook_dir)?;
+        fs::write(
+            hook_dir.join("post-init"),
+            "#!/bin/sh\necho post-init \"$@\" > ../hooks.log\n",
+        )?;
+        fs::set_permissions(
+            hook_dir.join("post-init"),
+            fs::Permissions::from_mode(0o755),
+        )?;
+        let dir = home.join("store");
+        fs::create_dir(&dir)?;
+        cmd.current_dir(&dir)
+            .env("XDG_CONFIG_HOME", home.join(".config"))
+            .arg("init")
+            .with_stdin()
+            .buffer("foobar\nfoobar\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert_eq!(fs::read_to_string(home.join("hooks.log"))?, "post-init\n");
+        Ok(())
+    })
+}
+
+// git
+
+fn git(home: &Path, dir: &Path, args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .env("HOME", home)
+        .args(args)
+        .output()?;
+    assert!(output.status.success());
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn git_config(home: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(home.join(".sala.toml"), "git-auto-commit = true\n")?;
+    fs::write(
+        home.join(".gitconfig"),
+        "[user]\nname = Test\nemail = test@example.com\n",
+    )?;
+    Ok(())
+}
+
+#[test]
+fn init_git() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, _| {
+        git_config(home)?;
+        let dir = home.join("store");
+        fs::create_dir(&dir)?;
+        cmd.current_dir(&dir)
+            .args(["init", "--git"])
+            .with_stdin()
+            .buffer("foobar\nfoobar\n")
+            .output()?
+            .assert()
+            .success();
+
+        assert!(fs::read_to_string(dir.join(".gitattributes"))?.contains("* -diff -merge"));
+        assert_eq!(
+            git(home, &dir, &["log", "--format=%s"])?,
+            "Initialize the password store\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn init_git_clean() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, _| {
+        fs::write(
+            home.join(".gitconfig"),
+            "[user]\nname = Test\nemail = test@example.com\n",
+        )?;
+        let dir = home.join("store");
+        fs::create_dir(&dir)?;
+        cmd.current_dir(&dir)
+            .args(["init", "--git"])
+            .with_stdin()
+            .buffer("foobar\nfoobar\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(git(home, &dir, &["status", "--short"])?, "");
+
+        sala(home)?
+            .current_dir(&dir)
+            .args(["config", "set", "git-auto-commit", "true"])
+            .assert()
+            .success();
+        assert_eq!(
+            git(home, &dir, &["log", "--format=%s"])?,
+            "Set git-auto-commit\nInitialize the password store\n"
+        );
+        assert_eq!(git(home, &dir, &["status", "--short"])?, "");
+        Ok(())
+    })
+}
+
+#[test]
+fn git_auto_commit() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        git_config(home)?;
+        git(home, &repo.path(), &["init", "--quiet"])?;
+        cmd.current_dir(repo.path())
+            .args(["set", NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\nsecret\nsecret\n")
+            .output()?
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["mv", NON_EXISTING_SECRET, "foo/@moved"])
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rm", "foo/@moved"])
+            .assert()
+            .success();
+
+        // Only the paths touched by sala are committed
+        assert_eq!(
+            git(home, &repo.path(), &["log", "--format=%s"])?,
+            "Remove foo/@moved\nMove foo/@new to foo/@moved\nAdd foo/@new\n"
+        );
+        assert_eq!(
+            git(home, &repo.path(), &["status", "--short"])?,
+            "?? .sala/\n?? foo/\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn git_command() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        git(home, &repo.path(), &["init", "--quiet"])?;
+        cmd.current_dir(home)
+            .args(["-C", &repo.path_string(), "git", "status", "--short"])
+            .assert()
+            .success()
+            .stdout(similar("?? .sala/\n?? foo/\n"));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["git", "nonexistent-command"])
+            .assert()
+            .failure();
+        Ok(())
+    })
+}
+
+// fsck
+
+#[test]
+fn fsck_success() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .arg("fsck")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar("\nChecked 1 secrets, no problems found\n"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn fsck_problems() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        fs::create_dir(repo.path().join("empty"))?;
+        fs::write(repo.path().join("plain"), "hunter2")?;
+        fs::write(repo.path().join("foo/@bar.tmp"), "")?;
+        fs::write(
+            repo.path().join("foo/@corrupt"),
+            "-----BEGIN PGP MESSAGE-----\n\n-----END PGP MESSAGE-----\n",
+        )?;
+        fs::set_permissions(
+            repo.path().join(EXISTING_SECRET),
+            fs::Permissions::from_mode(0o666),
+        )?;
+
+        cmd.current_dir(repo.path())
+            .arg("verify")
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .code(8)
+            .stdout(similar(
+                "
+empty: Empty directory
+foo/@bar: Writable by others (mode 666)
+foo/@bar.tmp: Leftover temporary file
+foo/@corrupt: Unable to decrypt: invalid OpenPGP message: no encrypted data
+plain: Not an armored OpenPGP message
+Checked 3 secrets, found 5 problems
+",
+            ));
+
+        Ok(())
+    })
+}
+
+// rules
+
+#[test]
+fn config_rules() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "password-length = 30\n\
+             [rules.\"legacy/*\"]\n\
+             password-length = 12\n\
+             password-classes = [\"digits\"]\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["set", "--generate", "legacy/@old"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        let secret = read_secret(home, repo, "legacy/@old")?;
+        assert_eq!(secret.chars().count(), 12);
+        assert!(secret.chars().all(|c| c.is_ascii_digit()));
+
+        // Only direct children match
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["set", "--generate", "legacy/deep/@old"])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success();
+        assert_eq!(
+            read_secret(home, repo, "legacy/deep/@old")?.chars().count(),
+            30
+        );
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rules", "legacy/@old"])
+            .assert()
+            .success()
+            .stdout(similar(
+                "[rules.\"legacy/*\"] in ./.sala/config\n\
+                 password-classes = [\"digits\"]\n\
+                 password-length = 12\n",
+            ));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["rules", EXISTING_SECRET])
+            .assert()
+            .success()
+            .stdout(similar("No rules apply to foo/@bar\n"));
+        Ok(())
+    })
+}
+
+#[test]
+fn config_rules_invalid() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "[rules.\"legacy/*\"]\nbackend = \"gpg\"\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .arg("rules")
+            .assert()
+            .code(1)
+            .stderr(similar(
+                "Error loading config file ./.sala/config:\n\
+                 backend cannot be set in rules.\"legacy/*\"\n",
+            ));
+        Ok(())
+    })
+}
+
+// config
+
+#[test]
+fn config_list_and_get() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "password-length = 30\n")?;
+        fs::write(repo.path().join(".sala/config"), "cipher = \"AES256\"\n")?;
+        let output = cmd
+            .current_dir(repo.path())
+            .args(["config", "list"])
+            .output()?;
+        output.clone().assert().success();
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("backend = \"native\"  # default\n"));
+        assert!(stdout.contains("cipher = \"AES256\"  # ./.sala/config\n"));
+        assert!(stdout.contains(&format!(
+            "password-length = 30  # {}\n",
+            home.join(".sala.toml").to_string_lossy()
+        )));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "get", "cipher"])
+            .assert()
+            .success()
+            .stdout(similar("AES256\n"));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "get", "password-generator"])
+            .assert()
+            .code(1)
+            .stdout(similar(""));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "get", "foo"])
+            .assert()
+            .code(2)
+            .stderr(similar("Error: Unknown setting: foo\n"));
+        Ok(())
+    })
+}
+
+#[test]
+fn config_set() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "# Shared settings\n\
+             password-classes = [\n    \"digits\",\n]\n\
+             \n\
+             [rules.\"legacy/*\"]\n\
+             password-length = 12\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .args(["config", "set", "password-classes", "[\"lowercase\"]"])
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "--repo", "cipher", "AES256"])
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "hook-policy.post-set", "abort"])
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(repo.path().join(".sala/config"))?,
+            "# Shared settings\n\
+             password-classes = [\"lowercase\"]\n\
+             cipher = \"AES256\"\n\
+             \n\
+             [rules.\"legacy/*\"]\n\
+             password-length = 12\n\
+             \n\
+             [hook-policy]\n\
+             post-set = \"abort\"\n"
+        );
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "--user", "password-length", "30"])
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(home.join(".config/sala.toml"))?,
+            "password-length = 30\n"
+        );
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "password-length", "long"])
+            .assert()
+            .code(1)
+            .stderr(predicate::str::starts_with(
+                "Error: Invalid config file ./.sala/config: invalid type: string \"long\"",
+            ));
+        Ok(())
+    })
+}
+
+#[test]
+fn config_validation() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "# Shared settings\ncipher = \"AES-129\"\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(
+                "Error loading config file ./.sala/config:\n\
+                 cipher `AES-129` is not supported by the native backend, expected one of \
+                 CAST5, AES128, AES192, AES256, TWOFISH, CAMELLIA128, CAMELLIA192, CAMELLIA256 \
+                 for key `cipher` at line 2\n",
+            );
+
+        // Ciphers are matched regardless of case and dashes
+        fs::write(
+            repo.path().join(".sala/config"),
+            "cipher = \"aes-256\"\n\
+             \n\
+             [rules.\"legacy/*\"]\n\
+             cipher = \"ROT13\"\n",
+        )?;
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(predicate::str::ends_with(
+                "cipher `ROT13` is not supported by the native backend, expected one of \
+                 CAST5, AES128, AES192, AES256, TWOFISH, CAMELLIA128, CAMELLIA192, CAMELLIA256 \
+                 for key `cipher` at line 4\n",
+            ));
+
+        fs::write(repo.path().join(".sala/config"), "key-length = 8\n")?;
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(
+                "Error loading config file ./.sala/config:\n\
+                 invalid value: integer `8`, expected at least 16 for key `key-length` at line 1\n",
+            );
+
+        // An invalid value is not written to the config file
+        fs::write(repo.path().join(".sala/config"), "cipher = \"AES192\"\n")?;
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "cipher", "DES"])
+            .assert()
+            .code(1)
+            .stderr(predicate::str::starts_with(
+                "Error: Invalid config file ./.sala/config: cipher `DES` is not supported",
+            ));
+        assert_eq!(
+            fs::read_to_string(repo.path().join(".sala/config"))?,
+            "cipher = \"AES192\"\n"
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn config_validation_timeouts() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(
+            repo.path().join(".sala/config"),
+            "agent-max-lifetime = 9223372036854775807\n",
+        )?;
+        cmd.current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(
+                "Error loading config file ./.sala/config:\n\
+                 invalid value: integer `9223372036854775807`, expected at most 31536000 \
+                 for key `agent-max-lifetime` at line 1\n",
+            );
+
+        fs::write(
+            repo.path().join(".sala/config"),
+            "hook-timeout = 9223372036854775807\n",
+        )?;
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(
+                "Error loading config file ./.sala/config:\n\
+                 invalid value: integer `9223372036854775807`, expected at most 31536000 \
+                 for key `hook-timeout` at line 1\n",
+            );
+
+        fs::write(
+            repo.path().join(".sala/config"),
+            "[rules.\"slow/*\"]\nhook-timeout = 9223372036854775807\n",
+        )?;
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(predicate::str::ends_with(
+                "for key `hook-timeout` at line 2\n",
+            ));
+        Ok(())
+    })
+}
+
+#[test]
+fn config_fix_invalid() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, home, repo| {
+        fs::write(home.join(".sala.toml"), "cipher = \"AES-129\"\n")?;
+        fs::write(repo.path().join(".sala/config"), "key-length = 8\n")?;
+
+        // The config commands work even if the config can't be loaded
+        cmd.current_dir(repo.path())
+            .args(["config", "get", "cipher"])
+            .assert()
+            .success()
+            .stdout(similar("AES-129\n"));
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "list"])
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "key-length = 8  # ./.sala/config\n",
+            ));
+
+        // One invalid setting can be fixed while another one is still invalid
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "--user", "cipher", "AES-128"])
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(home.join(".sala.toml"))?,
+            "cipher = \"AES-128\"\n"
+        );
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .code(1)
+            .stderr(predicate::str::contains("key-length"));
+
+        sala(home)?
+            .current_dir(repo.path())
+            .args(["config", "set", "key-length", "32"])
+            .assert()
+            .success();
+        sala(home)?
+            .current_dir(repo.path())
+            .arg("ls")
+            .assert()
+            .success();
+        Ok(())
+    })
+}
+
+// json
+
+#[test]
+fn get_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["--format", "json", "get", EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .success()
+            .stdout(similar(
+                r#"{"field":null,"hook_warnings":[],"path":"foo/@bar","secret":"baz","type":"secret"}
+"#,
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn get_many_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["--format", "json", "get", EXISTING_SECRET, NON_EXISTING_SECRET])
+            .with_stdin()
+            .buffer("qwerty\n")
+            .output()?
+            .assert()
+            .failure()
+            .stderr(similar("Enter the master passphrase: "))
+            .stdout(similar(
+                r#"{"hook_warnings":[],"secrets":[{"path":"foo/@bar","secret":"baz"},{"error":{"code":"file_does_not_exist","message":"File does not exist or invalid: foo/@new","path":"foo/@new"},"path":"foo/@new"}],"type":"secrets"}
+"#,
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn ls_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, _, repo| {
+        cmd.current_dir(repo.path())
+            .args(["ls", "--format", "json"])
+            .assert()
+            .success()
+            .stdout(similar(
+                r#"{"directory":"","secrets":["foo/@bar"],"type":"list"}
+"#,
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn error_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .args(["--format", "json", "ls"])
+            .assert()
+            .failure()
+            .stderr(similar(""))
+            .stdout(similar(
+                r#"{"code":"no_repo","message":"No repository. Run `sala init' first","type":"error"}
+"#,
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn usage_error_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .args(["--format", "json", "get"])
+            .assert()
+            .code(2)
+            .stderr(similar(""))
+            .stdout(predicate::str::starts_with(
+                r#"{"code":"usage","message":"error: The following required arguments were not provided:"#,
+            ));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn init_json() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .args(["--format", "json", "init"])
+            .with_stdin()
+            .buffer("foobar\nfoobar\n")
+            .output()?
+            .assert()
+            .success()
+            .stderr(predicate::str::contains(
+                "Generating a master key (512 bits)... done",
+            ))
+            .stdout(similar("{\"hook_warnings\":[],\"type\":\"ok\"}\n"));
+
+        Ok(())
+    })
+}
+
+// misc
+
+#[test]
+fn no_args() -> Result<(), Box<dyn Error>> {
+    run_test(|cmd, dir, _| {
+        cmd.current_dir(dir)
+            .assert()
+            .code(2)
             .stderr(similar("Try `sala --help'\n"));
 
         Ok(())